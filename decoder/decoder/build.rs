@@ -1,8 +1,9 @@
 use argon2::{Algorithm, Argon2, Params, Version};
 use ed25519_dalek::{SecretKey, SigningKey, PUBLIC_KEY_LENGTH};
-use rand::rngs::ThreadRng;
+use flash_layout::FlashLayout;
+use rand::rngs::StdRng;
 //use rand::seq::IteratorRandom;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::env;
@@ -26,7 +27,7 @@ pub fn force_rerun() {
 /// Generate an address that is a multiple of 8.
 ///
 /// Most need to be a multiple of 4, but stack needs to be a multiple of 8.
-fn gen_addr(start: u32, end: u32, rng: &mut ThreadRng) -> u32 {
+fn gen_addr(start: u32, end: u32, rng: &mut impl Rng) -> u32 {
     rng.gen_range((start / 8 + 1)..(end / 8)) * 8
 }
 
@@ -69,7 +70,20 @@ fn derive_key(root_key: &[u8], identifier: &[u8]) -> [u8; 32] {
 struct GlobalSecrets {
     subscribe_root_key: [u8; 32],
     subscribe_private_key: [u8; 32],
+    /// Subscription signing keys from before the last key rotations, most recent first.
+    ///
+    /// Subscriptions signed with these are still accepted.
+    #[serde(default)]
+    previous_subscribe_private_keys: Vec<[u8; 32]>,
     channels: HashMap<usize, ChannelSecrets>,
+    /// Channels which are always decodable, and use their root key as the encryption key.
+    #[serde(default = "default_emergency_channels")]
+    emergency_channels: Vec<usize>,
+}
+
+/// Secrets files without emergency channels listed only have channel 0.
+fn default_emergency_channels() -> Vec<usize> {
+    vec![0]
 }
 
 /// Secrets for an individual channel.
@@ -100,10 +114,14 @@ fn main() {
 
     // first generate subscription ChaCha20 an ed25519 keys, which is don using argon2
     let subscription_key = derive_key(&secrets.subscribe_root_key, &decoder_id.to_le_bytes());
-    let subscription_public_key = private_key_to_public_key(&derive_key(
-        &secrets.subscribe_private_key,
-        &decoder_id.to_le_bytes(),
-    ));
+    // current signing key is first, so it is tried first
+    let subscription_public_keys = [secrets.subscribe_private_key]
+        .iter()
+        .chain(&secrets.previous_subscribe_private_keys)
+        .map(|private_key| {
+            private_key_to_public_key(&derive_key(private_key, &decoder_id.to_le_bytes()))
+        })
+        .collect::<Vec<_>>();
 
     // generate rust code with necessary constants
     let mut rust_code = String::new();
@@ -118,12 +136,45 @@ fn main() {
     };
 
     add_bytes("SUBSCRIPTION_ENC_KEY", &subscription_key);
-    add_bytes("SUBSCRIPTION_PUBLIC_KEY", &subscription_public_key);
-    add_bytes("CHANNEL0_ENC_KEY", &secrets.channels[&0].root_key);
-    add_bytes(
-        "CHANNEL0_PUBLIC_KEY",
-        &private_key_to_public_key(&secrets.channels[&0].private_key),
+
+    rust_code.push_str(&format!(
+        "pub const SUBSCRIPTION_PUBLIC_KEY_COUNT: usize = {};\n",
+        subscription_public_keys.len(),
+    ));
+    rust_code.push_str(&format!(
+        "pub const SUBSCRIPTION_PUBLIC_KEYS: [[u8; 32]; SUBSCRIPTION_PUBLIC_KEY_COUNT] = {subscription_public_keys:?};\n",
+    ));
+
+    // emergency channel keys are hardcoded, since they don't need a subscription
+    assert!(
+        secrets.emergency_channels.contains(&0),
+        "channel 0 must be an emergency channel"
     );
+    let emergency_channel_count = secrets.emergency_channels.len();
+    let emergency_channel_enc_keys = secrets
+        .emergency_channels
+        .iter()
+        .map(|channel| secrets.channels[channel].root_key)
+        .collect::<Vec<_>>();
+    let emergency_channel_public_keys = secrets
+        .emergency_channels
+        .iter()
+        .map(|channel| private_key_to_public_key(&secrets.channels[channel].private_key))
+        .collect::<Vec<_>>();
+
+    rust_code.push_str(&format!(
+        "pub const EMERGENCY_CHANNEL_COUNT: usize = {emergency_channel_count};\n"
+    ));
+    rust_code.push_str(&format!(
+        "pub const EMERGENCY_CHANNEL_IDS: [u32; EMERGENCY_CHANNEL_COUNT] = {:?};\n",
+        secrets.emergency_channels,
+    ));
+    rust_code.push_str(&format!(
+        "pub const EMERGENCY_CHANNEL_ENC_KEYS: [[u8; 32]; EMERGENCY_CHANNEL_COUNT] = {emergency_channel_enc_keys:?};\n",
+    ));
+    rust_code.push_str(&format!(
+        "pub const EMERGENCY_CHANNEL_PUBLIC_KEYS: [[u8; 32]; EMERGENCY_CHANNEL_COUNT] = {emergency_channel_public_keys:?};\n",
+    ));
 
     // NOTE: We had to partially disable compile time aslr due to very bizzare issues with
     // the flash controller and certain builds just having issues
@@ -183,10 +234,21 @@ fn main() {
     // |--------------------------------------------------------------------------------|
     // | .bss                                                                           |
     // |--------------------------------------------------------------------------------|
-    const _FLASH_START: usize = 0x10000000;
-    const _FLASH_PAGE_SIZE: usize = 8192;
-
-    let mut rng = rand::thread_rng();
+    const FLASH_START: usize = 0x10000000;
+    const FLASH_PAGE_SIZE: usize = 8192;
+    const FLASH_PAGE_COUNT: usize = 64;
+
+    // one page per subscription, this is where MAX_SUBSCRIPTIONS comes from
+    const DATA_PAGE_COUNT: usize = 8;
+    const FIRST_DATA_PAGE: usize = 40;
+    const DATA_PAGE_SPACING: usize = 2;
+
+    // LAYOUT_SEED can be set to get the same layout every build, for debugging layout dependent issues
+    println!("cargo:rerun-if-env-changed=LAYOUT_SEED");
+    let mut rng = match env::var("LAYOUT_SEED") {
+        Ok(seed) => StdRng::seed_from_u64(seed.parse().expect("could not parse layout seed")),
+        Err(_) => StdRng::from_entropy(),
+    };
 
     let flash_length = 0x00038000;
     let ram_length = 0x00020000;
@@ -213,23 +275,29 @@ fn main() {
     let dataoffset = gen_addr(0, 0x2000, &mut rng);
     let bssoffset = gen_addr(0, ram_length / 16, &mut rng);
 
-    // now determine which 8 pages to use for storing flash data
-    // possible pages are 37 to 62 inclusive as described above
-    // let data_pages = 37..=62;
+    let flash_layout = FlashLayout {
+        flash_start: FLASH_START,
+        page_size: FLASH_PAGE_SIZE,
+        page_count: FLASH_PAGE_COUNT,
+        code_end: flash_origin + flash_length,
+    };
+
+    // now determine which DATA_PAGE_COUNT pages to use for storing flash data
+    // possible pages are described above
+    // let data_pages = flash_layout.usable_pages();
     // let used_data_pages = data_pages
-    //     .choose_multiple(&mut rng, 8)
+    //     .choose_multiple(&mut rng, DATA_PAGE_COUNT)
     //     .iter()
     //     .map(|page_number| FLASH_START + page_number * FLASH_PAGE_SIZE)
     //     .collect::<Vec<_>>();
-    // Disabled because of flash issues, hardcode pages used to be starting at 0x10050000,
+    // Disabled because of flash issues, pages used are fixed to start at page 40 (0x10050000),
     // with 1 gap in between each
-    let used_data_pages = [
-        0x10050000, 0x10054000, 0x10058000, 0x1005c000, 0x10060000, 0x10064000, 0x10068000,
-        0x1006c000,
-    ];
+    let used_data_pages =
+        flash_layout.spaced_pages(FIRST_DATA_PAGE, DATA_PAGE_COUNT, DATA_PAGE_SPACING);
 
     rust_code.push_str(&format!(
-        "pub const FLASH_DATA_ADDRS: [usize; 8] = {used_data_pages:?};\n",
+        "pub const FLASH_DATA_ADDRS: [usize; {}] = {used_data_pages:?};\n",
+        used_data_pages.len(),
     ));
 
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -259,10 +327,15 @@ fn main() {
         .unwrap();
 
     println!("cargo:rustc-link-search={}", out_path.display());
+    println!("cargo:rerun-if-changed=link.x");
+
+    // unit tests are built for the host, which can't use the board's linker script
+    if !env::var("TARGET").unwrap().starts_with("thumb") {
+        return;
+    }
 
     println!("cargo:rustc-link-arg=--nmagic");
 
     // FIXME: make sure we are not accidentally using cortex-m-rt linker script
     println!("cargo:rustc-link-arg=-Tlink.x");
-    println!("cargo:rerun-if-changed=link.x");
 }
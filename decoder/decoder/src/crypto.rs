@@ -14,9 +14,29 @@ struct DecoderPayloadHeader {
     poly1305_tag: [u8; 16],
 }
 
+/// Splits a decoder payload into its header and the rest of the payload (ciphertext and associated data).
+///
+/// Returns an error if the payload is too short to contain a header.
+fn parse_header(payload: &[u8]) -> Result<(DecoderPayloadHeader, &[u8]), DecoderError> {
+    let (header, body) = payload
+        .split_at_checked(size_of::<DecoderPayloadHeader>())
+        .ok_or(DecoderError::InvalidEncoderPayload)?;
+
+    Ok((pod_read_unaligned(header), body))
+}
+
+/// Same as [`parse_header`], but returns the rest of the payload mutably so it can be decrypted in place.
+fn parse_header_mut(payload: &mut [u8]) -> Result<(DecoderPayloadHeader, &mut [u8]), DecoderError> {
+    let (header, _) = parse_header(payload)?;
+
+    // parse_header checked the payload is long enough to contain the header
+    Ok((header, &mut payload[size_of::<DecoderPayloadHeader>()..]))
+}
+
 /// Verifies and decrypts any payload the decoder receives.
 ///
 /// This includes both satellite frames and subscription data.
+/// The payload is accepted if it is signed by any of `public_keys`.
 /// Decrypted data overwrites ciphertext in payload and a reference to this data is returned.
 ///
 /// # Payload Format
@@ -36,26 +56,56 @@ pub fn decrypt_decoder_payload<'a>(
     payload: &'a mut [u8],
     associated_data_size: usize,
     symmetric_key: &[u8; 32],
-    public_key: &VerifyingKey,
+    public_keys: &[VerifyingKey],
 ) -> Result<&'a [u8], DecoderError> {
-    if payload.len() < size_of::<DecoderPayloadHeader>() + associated_data_size {
+    let signed = public_keys.iter().any(|public_key| {
+        verify_decoder_payload(payload, associated_data_size, public_key).is_ok()
+    });
+    if !signed {
         return Err(DecoderError::InvalidEncoderPayload);
     }
 
-    let header: DecoderPayloadHeader =
-        pod_read_unaligned(&payload[..size_of::<DecoderPayloadHeader>()]);
+    decrypt_verified_decoder_payload(payload, associated_data_size, symmetric_key)
+}
+
+/// Verifies the signature of a decoder payload, without decrypting it.
+///
+/// This does not need the symmetric key, so callers can reject forged payloads
+/// before doing any work to derive the key. See [`decrypt_decoder_payload`] for the payload format.
+pub fn verify_decoder_payload(
+    payload: &[u8],
+    associated_data_size: usize,
+    public_key: &VerifyingKey,
+) -> Result<(), DecoderError> {
+    let (header, body) = parse_header(payload)?;
+    if body.len() < associated_data_size {
+        return Err(DecoderError::InvalidEncoderPayload);
+    }
 
-    // first verify signature
     // signature should include chacha nonce and tag, otherwise attacker can alter nonce and get invalid frame
     // decode for scenario 5 if they have the key
     let message_to_verify = &payload[SIGNATURE_LENGTH..];
     public_key
         .verify(message_to_verify, &Signature::from_bytes(&header.signature))
-        .map_err(|_| DecoderError::InvalidEncoderPayload)?;
+        .map_err(|_| DecoderError::InvalidEncoderPayload)
+}
+
+/// Decrypts a decoder payload which has already been checked with [`verify_decoder_payload`].
+///
+/// Decrypted data overwrites ciphertext in payload and a reference to this data is returned.
+pub fn decrypt_verified_decoder_payload<'a>(
+    payload: &'a mut [u8],
+    associated_data_size: usize,
+    symmetric_key: &[u8; 32],
+) -> Result<&'a [u8], DecoderError> {
+    let (header, body) = parse_header_mut(payload)?;
 
     // retrieve ciphertext and associated data
-    let body = &mut payload[size_of::<DecoderPayloadHeader>()..];
-    let (ciphertext, associated_data) = body.split_at_mut(body.len() - associated_data_size);
+    let ciphertext_len = body
+        .len()
+        .checked_sub(associated_data_size)
+        .ok_or(DecoderError::InvalidEncoderPayload)?;
+    let (ciphertext, associated_data) = body.split_at_mut(ciphertext_len);
 
     // then decrypt message
     let cipher = XChaCha20Poly1305::new(symmetric_key.into());
@@ -90,3 +140,131 @@ pub fn compute_chacha_block(key: [u8; 32]) -> [u8; 64] {
 
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    const SYMMETRIC_KEY: [u8; 32] = [7; 32];
+
+    /// Encrypts and signs `data` the same way the python design does.
+    fn encode_payload(data: &[u8], associated_data: &[u8], signing_key: &SigningKey) -> Vec<u8> {
+        let nonce = [3; 24];
+        let mut ciphertext = data.to_vec();
+        let tag = XChaCha20Poly1305::new(&SYMMETRIC_KEY.into())
+            .encrypt_in_place_detached(&nonce.into(), associated_data, &mut ciphertext)
+            .unwrap();
+
+        let mut signed = [&nonce[..], &tag[..], &ciphertext, associated_data].concat();
+        let signature = signing_key.sign(&signed).to_bytes();
+        signed.splice(0..0, signature);
+        signed
+    }
+
+    #[test]
+    fn subscription_signed_with_previous_key_is_accepted() {
+        // the current key is first, as in SUBSCRIPTION_PUBLIC_KEYS
+        let current = SigningKey::from_bytes(&[1; 32]);
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        let public_keys = [current.verifying_key(), previous.verifying_key()];
+
+        for signing_key in [&current, &previous] {
+            let mut payload = encode_payload(b"subscription", b"ad", signing_key);
+            let data = decrypt_decoder_payload(&mut payload, 2, &SYMMETRIC_KEY, &public_keys);
+            assert_eq!(data.unwrap(), b"subscription");
+        }
+    }
+
+    #[test]
+    fn subscription_signed_with_revoked_key_is_rejected() {
+        let current = SigningKey::from_bytes(&[1; 32]);
+        let previous = SigningKey::from_bytes(&[2; 32]);
+        // rotated out, so decoders are no longer built with it
+        let revoked = SigningKey::from_bytes(&[3; 32]);
+        let public_keys = [current.verifying_key(), previous.verifying_key()];
+
+        let mut payload = encode_payload(b"subscription", b"ad", &revoked);
+        let original = payload.clone();
+        assert!(matches!(
+            decrypt_decoder_payload(&mut payload, 2, &SYMMETRIC_KEY, &public_keys),
+            Err(DecoderError::InvalidEncoderPayload)
+        ));
+
+        // rejected before decrypting anything
+        assert_eq!(payload, original);
+    }
+
+    /// Size of the payload header: signature, then nonce, then tag.
+    const HEADER_SIZE: usize = SIGNATURE_LENGTH + 24 + 16;
+
+    /// Payload whose byte at each offset is the offset itself, so fields can be checked by position.
+    fn counting_payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn check_header(header: &DecoderPayloadHeader) {
+        let expected = counting_payload(HEADER_SIZE);
+        assert_eq!(header.signature, expected[..64]);
+        assert_eq!(header.chacha_nonce, expected[64..88]);
+        assert_eq!(header.poly1305_tag, expected[88..104]);
+    }
+
+    #[test]
+    fn header_size() {
+        assert_eq!(size_of::<DecoderPayloadHeader>(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn parse_header_reads_fields_at_offsets() {
+        let mut payload = counting_payload(HEADER_SIZE + 3);
+
+        let (header, body) = parse_header(&payload).unwrap();
+        check_header(&header);
+        assert_eq!(body, [104, 105, 106]);
+
+        let (header, body) = parse_header_mut(&mut payload).unwrap();
+        check_header(&header);
+        assert_eq!(body, [104, 105, 106]);
+    }
+
+    #[test]
+    fn parse_header_minimum_length() {
+        let mut payload = counting_payload(HEADER_SIZE);
+
+        let (header, body) = parse_header(&payload).unwrap();
+        check_header(&header);
+        assert!(body.is_empty());
+
+        let (header, body) = parse_header_mut(&mut payload).unwrap();
+        check_header(&header);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn parse_header_one_byte_short() {
+        let mut payload = counting_payload(HEADER_SIZE - 1);
+
+        assert!(matches!(
+            parse_header(&payload),
+            Err(DecoderError::InvalidEncoderPayload)
+        ));
+        assert!(matches!(
+            parse_header_mut(&mut payload),
+            Err(DecoderError::InvalidEncoderPayload)
+        ));
+        assert!(parse_header(&[]).is_err());
+    }
+
+    #[test]
+    fn parse_header_mut_body_writes_after_header() {
+        let mut payload = counting_payload(HEADER_SIZE + 2);
+
+        let (_, body) = parse_header_mut(&mut payload).unwrap();
+        body.fill(0xff);
+
+        assert_eq!(payload[..HEADER_SIZE], counting_payload(HEADER_SIZE));
+        assert_eq!(payload[HEADER_SIZE..], [0xff, 0xff]);
+    }
+}
@@ -1,13 +1,18 @@
 use bytemuck::{try_from_bytes, Pod, Zeroable};
 use ed25519_dalek::VerifyingKey;
+use zeroize::Zeroizing;
 
 use crate::crypto::{
-    compute_chacha_block, decrypt_decoder_payload, get_decoder_payload_associated_data,
+    compute_chacha_block, decrypt_verified_decoder_payload, get_decoder_payload_associated_data,
+    verify_decoder_payload,
 };
-use crate::decoder_context::{ChannelCache, CompressedSubscriptionEntry, KeySubtree};
-use crate::ectf_params::{CHANNEL0_ENC_KEY, EMERGENCY_CHANNEL_ID};
+use crate::decoder_context::{
+    ChannelCache, CompressedSubscriptionEntry, DecodeFailure, KeySubtree,
+};
+use crate::ectf_params::{emergency_channel_index, EMERGENCY_CHANNEL_ENC_KEYS, REPLAY_WINDOW};
 use crate::message::{Message, Opcode};
 use crate::println;
+use crate::utils::Cursor;
 use crate::{decoder_context::DecoderContext, DecoderError};
 
 /// Non-encrypted associated data sent with frame.
@@ -20,6 +25,9 @@ struct FrameAssociatedData {
     channel_id: u32,
 }
 
+/// Maximum number of bytes in a decoded frame.
+pub const MAX_FRAME_SIZE: usize = 64;
+
 /// Data in encoded frames.
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
@@ -29,59 +37,181 @@ struct FrameData {
     /// Data of frame.
     ///
     /// Extra bytes are zeroed out.
-    frame_data: [u8; 64],
+    frame_data: [u8; MAX_FRAME_SIZE],
 }
 
 /// Performs all functionality related to decoding frames.
+///
+/// If decoding fails, the error is also recorded as the last decode failure in `context`.
 pub fn decode(context: &mut DecoderContext, encoded_frame: &mut [u8]) -> Result<(), DecoderError> {
+    // read before decoding, since decoding overwrites the frame
+    let timestamp = get_decoder_payload_associated_data::<FrameAssociatedData>(encoded_frame)
+        .ok()
+        .map(|frame_info| frame_info.timestamp);
+
+    let mut frame = [0; MAX_FRAME_SIZE];
+    let result = decode_to_buffer(context, encoded_frame, &mut frame).and_then(|frame_len| {
+        let message = Message::from_data(Opcode::Decode, &frame[..frame_len]);
+        Ok(message.write()?)
+    });
+
+    if let Err(error) = &result {
+        context.last_decode_failure = Some(DecodeFailure {
+            code: error.code(),
+            timestamp,
+        });
+    }
+
+    result
+}
+
+/// Verifies and decodes a frame, writing the decoded frame into `out` and returning its length.
+///
+/// This does not talk to the host tools, so it can be run on any bytes without a uart.
+/// `context` is updated with the timestamp of the frame if decoding succeeds.
+pub fn decode_to_buffer(
+    context: &mut DecoderContext,
+    encoded_frame: &mut [u8],
+    out: &mut [u8],
+) -> Result<usize, DecoderError> {
     let frame_info: FrameAssociatedData = get_decoder_payload_associated_data(encoded_frame)?;
 
     // check frame we are decoding is monotonically increasing for security requirement 3
-    if context
-        .last_decoded_timestamp
-        .is_some_and(|last_decoded| frame_info.timestamp <= last_decoded)
-    {
-        return Err(DecoderError::NonMonotonicTimestamp);
-    }
+    context
+        .decoded_timestamps
+        .check_not_replayed(frame_info.timestamp, REPLAY_WINDOW)?;
+
+    let public_key = get_public_key_for_channel(context, frame_info.channel_id)?;
 
-    let (symmetric_key, public_key) =
-        get_keys_for_channel(context, frame_info.channel_id, frame_info.timestamp)?;
+    // verify signature before deriving the symmetric key,
+    // so forged frames are rejected without walking the key tree
+    verify_decoder_payload(encoded_frame, size_of::<FrameAssociatedData>(), &public_key)?;
+
+    let symmetric_key = Zeroizing::new(get_symmetric_key_for_channel(
+        context,
+        frame_info.channel_id,
+        frame_info.timestamp,
+    )?);
 
     // frame data has 1 byte at the start indicating how long it is
     // and 64 bytes after containing the data itself
     // this is to not leak length of frame (probably doesn't matter at all)
-    let frame_data = decrypt_decoder_payload(
+    let frame_data = decrypt_verified_decoder_payload(
         encoded_frame,
         size_of::<FrameAssociatedData>(),
         &symmetric_key,
-        &public_key,
     )?;
 
     // shouldn't have alignmanet issues, frame data is 1 byte aligned
     let frame_data: &FrameData = try_from_bytes(frame_data)?;
 
+    let frame = frame_data
+        .frame_data
+        .get(..frame_data.frame_len as usize)
+        .ok_or(DecoderError::InvalidEncoderPayload)?;
+    Cursor::new(out).read_from(frame)?;
+
     // decoding succeeded, update last decoded timestamp
-    context.last_decoded_timestamp = Some(frame_info.timestamp);
+    context.decoded_timestamps.record(frame_info.timestamp);
+
+    Ok(frame.len())
+}
+
+/// Tracks which timestamps have been decoded, to reject replayed frames.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodedTimestamps {
+    /// Timestamp of the newest decoded frame, or `None` if no frame has been decoded
+    last: Option<u64>,
+    /// Bitmap of timestamps in the replay window which have been decoded.
+    ///
+    /// Bit `i` is set if `last - i` was decoded.
+    window: u64,
+}
+
+impl DecodedTimestamps {
+    /// Creates a tracker with no decoded timestamps.
+    pub const fn new() -> Self {
+        DecodedTimestamps {
+            last: None,
+            window: 0,
+        }
+    }
+
+    /// Returns the timestamp of the newest decoded frame, or `None` if no frame has been decoded.
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Checks that a frame with `timestamp` is newer than the last decoded frame,
+    /// or is within `replay_window` of it and has not been decoded yet.
+    ///
+    /// `replay_window` is at most 64, see [`REPLAY_WINDOW`].
+    pub fn check_not_replayed(
+        &self,
+        timestamp: u64,
+        replay_window: u64,
+    ) -> Result<(), DecoderError> {
+        let Some(last_decoded) = self.last else {
+            return Ok(());
+        };
+
+        if timestamp > last_decoded {
+            return Ok(());
+        }
+
+        let age = last_decoded - timestamp;
+        if age >= replay_window || self.window & (1 << age) != 0 {
+            Err(DecoderError::NonMonotonicTimestamp)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks `timestamp` as decoded, which must have been checked by [`DecodedTimestamps::check_not_replayed`].
+    pub fn record(&mut self, timestamp: u64) {
+        match self.last {
+            Some(last_decoded) if timestamp <= last_decoded => {
+                self.window |= 1 << (last_decoded - timestamp);
+            }
+            last_decoded => {
+                // shift window so bit 0 is the new newest timestamp
+                let shift = last_decoded.map_or(u64::MAX, |last_decoded| timestamp - last_decoded);
+                let shifted_window = if shift < 64 { self.window << shift } else { 0 };
+                self.window = shifted_window | 1;
+                self.last = Some(timestamp);
+            }
+        }
+    }
+}
 
-    let message = Message::from_data(
-        Opcode::Decode,
-        &frame_data.frame_data[..frame_data.frame_len as usize],
-    );
-    message.write()?;
+/// Retrieve the public key for a frame on channel `channel_id`.
+fn get_public_key_for_channel(
+    context: &mut DecoderContext,
+    channel_id: u32,
+) -> Result<VerifyingKey, DecoderError> {
+    if let Some(index) = emergency_channel_index(channel_id) {
+        // emergency channel keys are hardcoded
+        Ok(context.emergency_channel_public_keys[index])
+    } else {
+        // other channel public keys are stored with subscription data
+        let Some((_, cache)) = context.get_subscription_for_channel(channel_id) else {
+            return Err(DecoderError::InvalidSubscription);
+        };
 
-    Ok(())
+        Ok(cache.public_key)
+    }
 }
 
-/// Retrieve the public and symmetric keys for a frame on channel `channel_number` encoded with
+/// Retrieve the symmetric key for a frame on channel `channel_id` encoded with
 /// timestamp `timestamp`.
-fn get_keys_for_channel(
+fn get_symmetric_key_for_channel(
     context: &mut DecoderContext,
     channel_id: u32,
     timestamp: u64,
-) -> Result<([u8; 32], VerifyingKey), DecoderError> {
-    if channel_id == EMERGENCY_CHANNEL_ID {
+) -> Result<[u8; 32], DecoderError> {
+    if let Some(index) = emergency_channel_index(channel_id) {
         // emergency channel keys are hardcoded
-        Ok((CHANNEL0_ENC_KEY, context.emergency_channel_public_key))
+        Ok(EMERGENCY_CHANNEL_ENC_KEYS[index])
     } else {
         // other channel keys are derived from subscription data
         let Some((subscription, cache)) = context.get_subscription_for_channel(channel_id) else {
@@ -89,16 +219,15 @@ fn get_keys_for_channel(
         };
 
         // derive symmetric key based on subscription data and timestamp
-        let symmetric_key = derive_decoder_key_for_timestamp(subscription, cache, timestamp)?;
-
-        Ok((symmetric_key, cache.public_key))
+        derive_decoder_key_for_timestamp(subscription, cache, timestamp)
     }
 }
 
 /// Derives a symmetric key for the given `timestamp` using subscription data.
 ///
 /// This uses the GGM key tree discussed in design doc.
-fn derive_decoder_key_for_timestamp(
+/// Every key derived on the way to the leaf is left in `cache`.
+pub fn derive_decoder_key_for_timestamp(
     subscription: &CompressedSubscriptionEntry,
     cache: &mut ChannelCache,
     timestamp: u64,
@@ -138,20 +267,20 @@ fn derive_decoder_key_for_timestamp(
     assert!(subtree.contains(timestamp));
 
     // shrink upper and lower bounds until we have found the key
-    while subtree.lowest_timestamp != subtree.highest_timestamp {
-        let expanded_key = compute_chacha_block(subtree.key);
+    for step in timestamp_path(
+        subtree.lowest_timestamp,
+        subtree.highest_timestamp,
+        timestamp,
+    ) {
+        // only one half is kept in the cache, the other half should not stay on the stack
+        let expanded_key = Zeroizing::new(compute_chacha_block(subtree.key));
 
-        // can't do (upper + lower) / 2 because this could integer overflow
-        let region_size = subtree.highest_timestamp - subtree.lowest_timestamp;
-        let lower_midsection = subtree.lowest_timestamp + (region_size >> 1);
-        let upper_midsection = lower_midsection + 1;
+        subtree.lowest_timestamp = step.lowest;
+        subtree.highest_timestamp = step.highest;
 
-        if timestamp <= lower_midsection {
-            subtree.key.copy_from_slice(&expanded_key[..32]);
-            subtree.highest_timestamp = lower_midsection;
-        } else {
-            subtree.key.copy_from_slice(&expanded_key[32..]);
-            subtree.lowest_timestamp = upper_midsection;
+        match step.direction {
+            Direction::Left => subtree.key.copy_from_slice(&expanded_key[..32]),
+            Direction::Right => subtree.key.copy_from_slice(&expanded_key[32..]),
         }
 
         // add the new subtree into the cache
@@ -160,3 +289,293 @@ fn derive_decoder_key_for_timestamp(
 
     Ok(subtree.key)
 }
+
+/// Which half of a node's timestamp range a child in the GGM key tree covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// Child covering the lower half, derived from the first 32 bytes of the expanded key.
+    Left,
+    /// Child covering the upper half, derived from the last 32 bytes of the expanded key.
+    Right,
+}
+
+impl Direction {
+    /// Returns the bounds of the child in this direction of the node covering `lowest..=highest`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the node is a leaf (`lowest == highest`), since leaves have no children.
+    fn apply(self, lowest: u64, highest: u64) -> (u64, u64) {
+        assert!(lowest < highest, "leaf node has no children");
+
+        // can't do (upper + lower) / 2 because this could integer overflow
+        let region_size = highest - lowest;
+        let lower_midsection = lowest + (region_size >> 1);
+        // lower_midsection is always below highest, so this can't overflow even if highest is u64::MAX
+        let upper_midsection = lower_midsection + 1;
+
+        match self {
+            Direction::Left => (lowest, lower_midsection),
+            Direction::Right => (upper_midsection, highest),
+        }
+    }
+}
+
+/// One step down the GGM key tree towards a leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PathStep {
+    /// Which child of the previous node this step goes to.
+    direction: Direction,
+    /// Lowest timestamp covered by the child.
+    lowest: u64,
+    /// Highest timestamp covered by the child.
+    highest: u64,
+}
+
+/// Returns the steps to take from the node covering `lowest..=highest`
+/// to reach the leaf for `target`.
+///
+/// This only deals with timestamp bounds, no keys are derived.
+///
+/// # Panics
+///
+/// Panics if `target` is not in `lowest..=highest`.
+fn timestamp_path(
+    mut lowest: u64,
+    mut highest: u64,
+    target: u64,
+) -> impl Iterator<Item = PathStep> {
+    assert!(lowest <= target && target <= highest);
+
+    core::iter::from_fn(move || {
+        if lowest == highest {
+            return None;
+        }
+
+        let (_, lower_midsection) = Direction::Left.apply(lowest, highest);
+        let direction = if target <= lower_midsection {
+            Direction::Left
+        } else {
+            Direction::Right
+        };
+
+        (lowest, highest) = direction.apply(lowest, highest);
+
+        Some(PathStep {
+            direction,
+            lowest,
+            highest,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rand_chacha::ChaCha8Rng;
+    use rand_core::{RngCore, SeedableRng};
+
+    use super::*;
+
+    /// Key tree walk as it was written before it was split into [`timestamp_path`].
+    fn reference_derive_key(mut subtree: KeySubtree, timestamp: u64) -> [u8; 32] {
+        while subtree.lowest_timestamp != subtree.highest_timestamp {
+            let expanded_key = compute_chacha_block(subtree.key);
+
+            let region_size = subtree.highest_timestamp - subtree.lowest_timestamp;
+            let lower_midsection = subtree.lowest_timestamp + (region_size >> 1);
+            let upper_midsection = lower_midsection + 1;
+
+            if timestamp <= lower_midsection {
+                subtree.key.copy_from_slice(&expanded_key[..32]);
+                subtree.highest_timestamp = lower_midsection;
+            } else {
+                subtree.key.copy_from_slice(&expanded_key[32..]);
+                subtree.lowest_timestamp = upper_midsection;
+            }
+        }
+
+        subtree.key
+    }
+
+    /// Path to `target` in a tree that is `2^height` wide, read off the bits of its offset.
+    ///
+    /// Key tree nodes are always a power of 2 wide, so each bit of the offset from
+    /// `lowest` picks a half, most significant bit first.
+    fn reference_path(lowest: u64, height: u32, target: u64) -> Vec<PathStep> {
+        let offset = target - lowest;
+        (0..height)
+            .rev()
+            .map(|bit| {
+                let direction = if offset >> bit & 1 == 0 {
+                    Direction::Left
+                } else {
+                    Direction::Right
+                };
+                // bits at and above `bit` select the child, bits below it span the child
+                let child_lowest = lowest + (offset >> bit << bit);
+                let child_highest = child_lowest + tree_width(bit);
+                PathStep {
+                    direction,
+                    lowest: child_lowest,
+                    highest: child_highest,
+                }
+            })
+            .collect()
+    }
+
+    /// Difference between the highest and lowest timestamp of a tree with `2^height` leaves.
+    fn tree_width(height: u32) -> u64 {
+        ((1u128 << height) - 1) as u64
+    }
+
+    #[test]
+    fn path_matches_bits_of_offset_for_every_leaf() {
+        for height in 0..=10 {
+            for lowest in [0, 1 << 20, u64::MAX - tree_width(height)] {
+                let highest = lowest + tree_width(height);
+                for target in lowest..=highest {
+                    let path: Vec<_> = timestamp_path(lowest, highest, target).collect();
+                    assert_eq!(path, reference_path(lowest, height, target));
+                    if let Some(leaf) = path.last() {
+                        assert_eq!((leaf.lowest, leaf.highest), (target, target));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn path_matches_bits_of_offset_in_wide_trees() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for height in [32, 48, 63, 64] {
+            let lowest = if height == 64 {
+                0
+            } else {
+                rng.next_u64() >> height << height
+            };
+            let highest = lowest + tree_width(height);
+            let mut targets = [lowest, highest, 0, 0];
+            for target in &mut targets[2..] {
+                *target = lowest + (rng.next_u64() & tree_width(height));
+            }
+
+            for target in targets {
+                let path: Vec<_> = timestamp_path(lowest, highest, target).collect();
+                assert_eq!(path, reference_path(lowest, height, target));
+            }
+        }
+    }
+
+    /// Checks `timestamp` with a replay window of 8, and records it if it passes.
+    fn decode_timestamp(decoded: &mut DecodedTimestamps, timestamp: u64) -> bool {
+        let accepted = decoded.check_not_replayed(timestamp, 8).is_ok();
+        if accepted {
+            decoded.record(timestamp);
+        }
+        accepted
+    }
+
+    #[test]
+    fn replay_window_accepts_reordered_frames() {
+        let mut decoded = DecodedTimestamps::new();
+        for timestamp in [100, 98, 103, 99, 101, 96, 102] {
+            assert!(
+                decode_timestamp(&mut decoded, timestamp),
+                "timestamp {timestamp}"
+            );
+        }
+        assert_eq!(decoded.last(), Some(103));
+    }
+
+    #[test]
+    fn replay_window_rejects_duplicates() {
+        let mut decoded = DecodedTimestamps::new();
+        for timestamp in [100, 97, 105] {
+            assert!(decode_timestamp(&mut decoded, timestamp));
+        }
+
+        // newest, in window before the window moved, and in window after it moved
+        for timestamp in [105, 100] {
+            assert!(
+                !decode_timestamp(&mut decoded, timestamp),
+                "timestamp {timestamp}"
+            );
+        }
+        assert!(decode_timestamp(&mut decoded, 104));
+        assert!(!decode_timestamp(&mut decoded, 104));
+        assert_eq!(decoded.last(), Some(105));
+    }
+
+    #[test]
+    fn replay_window_drops_frames_outside_window() {
+        let mut decoded = DecodedTimestamps::new();
+        assert!(decode_timestamp(&mut decoded, 100));
+
+        // oldest timestamp in the window, then one past it
+        assert!(decode_timestamp(&mut decoded, 93));
+        assert!(!decode_timestamp(&mut decoded, 92));
+        assert!(!decode_timestamp(&mut decoded, 0));
+
+        // a jump further than the window forgets everything older than the new frame
+        assert!(decode_timestamp(&mut decoded, 200));
+        assert!(!decode_timestamp(&mut decoded, 192));
+        assert!(decode_timestamp(&mut decoded, 193));
+    }
+
+    #[test]
+    fn zero_replay_window_only_accepts_newer_frames() {
+        let mut decoded = DecodedTimestamps::new();
+        assert!(decoded.check_not_replayed(0, 0).is_ok());
+        decoded.record(0);
+
+        assert!(decoded.check_not_replayed(0, 0).is_err());
+        assert!(decoded.check_not_replayed(1, 0).is_ok());
+        decoded.record(5);
+        assert!(decoded.check_not_replayed(4, 0).is_err());
+        assert!(decoded.check_not_replayed(u64::MAX, 0).is_ok());
+    }
+
+    #[test]
+    fn derived_keys_match_reference_walk() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        let mut subscription: CompressedSubscriptionEntry = Zeroable::zeroed();
+        subscription.start_time = 1000;
+        subscription.subtree_count = 3;
+        subscription.depths[..3].copy_from_slice(&[54, 55, 60]);
+        for key in &mut subscription.node_keys[..3] {
+            rng.fill_bytes(key);
+        }
+
+        let mut cache = ChannelCache {
+            public_key: SigningKey::from_bytes(&[1; 32]).verifying_key(),
+            cache_entries: Default::default(),
+        };
+
+        let end_time = subscription.checked_end_time().unwrap();
+        let mut timestamps = vec![1000, 1001, 1000 + 1023, 1000 + 1024, end_time - 1, end_time];
+        timestamps.extend((0..64).map(|_| 1000 + rng.next_u64() % (end_time - 1000 + 1)));
+        // sorted timestamps mostly hit the cache, the unsorted ones before this mostly miss it
+        let mut sorted = timestamps.clone();
+        sorted.sort();
+        timestamps.extend(sorted);
+
+        for timestamp in timestamps {
+            let expected =
+                reference_derive_key(subscription.get_subtree(timestamp).unwrap(), timestamp);
+
+            let key =
+                derive_decoder_key_for_timestamp(&subscription, &mut cache, timestamp).unwrap();
+            assert_eq!(key, expected, "timestamp {timestamp}");
+
+            let mut empty_cache = ChannelCache {
+                cache_entries: Default::default(),
+                ..cache
+            };
+            let key = derive_decoder_key_for_timestamp(&subscription, &mut empty_cache, timestamp)
+                .unwrap();
+            assert_eq!(key, expected, "timestamp {timestamp} without cache");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use bytemuck::{bytes_of, Pod, Zeroable};
+use bytemuck::{bytes_of, bytes_of_mut, Pod, Zeroable};
 use core::marker::PhantomData;
 use ed25519_dalek::VerifyingKey;
 use max78000_hal::mpu::{MemoryCacheType, MpuPerms, MpuRegionSize};
@@ -6,23 +6,58 @@ use max78000_hal::Icc;
 use thiserror_no_std::Error;
 
 use max78000_hal::flash::{FLASH_BASE_ADDR, FLASH_PAGE_SIZE, FLASH_SIZE, PAGE_MASK};
-use max78000_hal::{Flash, Peripherals};
+use max78000_hal::wdt::ResetCause;
+use max78000_hal::{Flash, Gcr, Peripherals, Trng};
 
 use tinyvec::ArrayVec;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::decode::{derive_decoder_key_for_timestamp, DecodedTimestamps};
 
 use crate::ectf_params::{
-    CHANNEL0_PUBLIC_KEY, FLASH_DATA_ADDRS, MAX_SUBSCRIPTIONS, SUBSCRIPTION_PUBLIC_KEY,
+    EMERGENCY_CHANNEL_COUNT, EMERGENCY_CHANNEL_PUBLIC_KEYS, FLASH_DATA_ADDRS,
+    FLASH_WRITE_COUNT_WARNING, MAX_SUBSCRIPTIONS, SUBSCRIPTION_PUBLIC_KEYS,
+    SUBSCRIPTION_PUBLIC_KEY_COUNT,
 };
+use crate::println;
+use crate::DecoderError;
+
+/// Status of a flash entry which had its object removed, but still keeps track of the write count.
+const FLASH_ENTRY_EMPTY_MAGIC: u32 = 0x22bb0066;
+
+/// An object which can be stored in a [`FlashEntry`].
+pub trait FlashPersistable: Pod {
+    /// Status of a flash entry which contains this type of object.
+    ///
+    /// Must not be [`FLASH_ENTRY_EMPTY_MAGIC`].
+    const MAGIC: u32;
+    /// Version of the layout of this type in flash.
+    ///
+    /// This must be changed whenever the layout changes, so old objects are not misinterpreted.
+    /// Objects written before versions were stored have version 0.
+    const VERSION: u32;
+}
 
-const FLASH_ENTRY_MAGIC: u32 = 0x11aa0055;
+/// What a [`FlashEntry`] contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashEntryStatus {
+    /// Entry contains an object with the current version.
+    Valid,
+    /// Entry contains an object written by firmware with a different layout version, which can't be read.
+    ///
+    /// There is no migration between versions, these objects should just be cleared.
+    Incompatible { version: u32 },
+    /// Entry does not contain an object.
+    Empty,
+}
 
 /// Stores an object on a page of flash
-pub struct FlashEntry<T: Pod> {
+pub struct FlashEntry<T: FlashPersistable> {
     address: usize,
     _marker: PhantomData<T>,
 }
 
-impl<T: Pod> FlashEntry<T> {
+impl<T: FlashPersistable> FlashEntry<T> {
     /// Creates a new `FlashEntry`.
     ///
     /// # Safety
@@ -64,8 +99,92 @@ impl<T: Pod> FlashEntry<T> {
         unsafe { core::ptr::read_volatile(ptr) }
     }
 
+    /// Retreive the layout version of the object stored in this entry.
+    ///
+    /// The version is stored in the status section after the write count.
+    /// Pages written before the version existed had these bytes zero padded, so they are version 0.
+    fn version(&self) -> u32 {
+        let ptr = (self.status_address() + 2 * size_of::<u32>()) as *const u32;
+
+        // safety: version is stored in status section on the same flash page, and is a valid u32
+        unsafe { core::ptr::read_volatile(ptr) }
+    }
+
+    /// Retreive the sequence number of the object stored in this entry.
+    ///
+    /// The sequence number is stored in the status section after the version.
+    /// Every write gets a bigger sequence number than any write before it,
+    /// so if two entries contain copies of the same object, the one with the bigger sequence number is newer.
+    /// Pages written before sequence numbers existed had these bytes zero padded, so they have sequence number 0.
+    pub fn sequence(&self) -> u32 {
+        let ptr = (self.status_address() + 3 * size_of::<u32>()) as *const u32;
+
+        // safety: sequence number is stored in status section on the same flash page, and is a valid u32
+        unsafe { core::ptr::read_volatile(ptr) }
+    }
+
+    /// Checks if this entry contains a readable object.
+    pub fn entry_status(&self) -> FlashEntryStatus {
+        Self::entry_status_from(self.status(), self.version())
+    }
+
+    /// Gets what an entry with the given status magic and layout version contains.
+    fn entry_status_from(status: u32, version: u32) -> FlashEntryStatus {
+        if status != T::MAGIC {
+            return FlashEntryStatus::Empty;
+        }
+
+        if version == T::VERSION {
+            FlashEntryStatus::Valid
+        } else {
+            FlashEntryStatus::Incompatible { version }
+        }
+    }
+
     pub fn has_object(&self) -> bool {
-        self.status() == FLASH_ENTRY_MAGIC
+        self.entry_status() == FlashEntryStatus::Valid
+    }
+
+    /// Returns how many times this entry has been written.
+    ///
+    /// The count is stored in the status section right after the magic.
+    /// Pages written before the counter existed had these bytes zero padded, so they start counting from 0.
+    /// A page that has never been written has no valid count, so it is also treated as 0.
+    pub fn write_count(&self) -> u32 {
+        let status = self.status();
+        if status != T::MAGIC && status != FLASH_ENTRY_EMPTY_MAGIC {
+            return 0;
+        }
+
+        let ptr = (self.status_address() + size_of::<u32>()) as *const u32;
+
+        // safety: count is stored in status section on the same flash page, and is a valid u32
+        unsafe { core::ptr::read_volatile(ptr) }
+    }
+
+    /// Gets the write count to store for the next write of this entry.
+    ///
+    /// Prints a warning if the page is getting close to its endurance limit.
+    fn next_write_count(&self) -> u32 {
+        let write_count = self.write_count().saturating_add(1);
+        if write_count >= FLASH_WRITE_COUNT_WARNING {
+            println!(
+                "Warning: flash page {:#x} has been written {write_count} times",
+                self.address
+            );
+        }
+
+        write_count
+    }
+
+    /// Constructs the contents of the status section.
+    fn status_bytes(magic: u32, write_count: u32, sequence: u32) -> [u8; 16] {
+        let mut status = [0; 16];
+        status[..4].copy_from_slice(&magic.to_ne_bytes());
+        status[4..8].copy_from_slice(&write_count.to_ne_bytes());
+        status[8..12].copy_from_slice(&T::VERSION.to_ne_bytes());
+        status[12..].copy_from_slice(&sequence.to_ne_bytes());
+        status
     }
 
     pub fn get(&self) -> Option<&T> {
@@ -77,12 +196,14 @@ impl<T: Pod> FlashEntry<T> {
         }
     }
 
-    /// Sets the contents of the flash entry.
-    /// 
+    /// Sets the contents of the flash entry, marking it with `sequence`.
+    ///
+    /// `sequence` must be bigger than the sequence number of every entry written before, see [`FlashEntry::sequence`].
+    ///
     /// # Safety
-    /// 
+    ///
     /// Must ensure the ICC is disabled before calling this.
-    pub unsafe fn set(&mut self, object: &T) {
+    pub unsafe fn set(&mut self, object: &T, sequence: u32) {
         let flash = Flash::get();
 
         // convert object to bytes
@@ -91,6 +212,9 @@ impl<T: Pod> FlashEntry<T> {
         // 16 bytes for status at end
         assert!(data.len() < FLASH_PAGE_SIZE - 16);
 
+        // read count before erasing page
+        let status = Self::status_bytes(T::MAGIC, self.next_write_count(), sequence);
+
         unsafe {
             // erase page first
             // safety: no references should be at this page, since no references are returned on get
@@ -107,7 +231,33 @@ impl<T: Pod> FlashEntry<T> {
             // write status after whole object written
             // safety: status resides within this flash page
             flash
-                .write(self.status_address(), &FLASH_ENTRY_MAGIC.to_ne_bytes())
+                .write(self.status_address(), &status)
+                .expect("failed to write status to flash");
+        }
+    }
+
+    /// Removes the object from the flash entry, keeping the write count of the page.
+    ///
+    /// The object is overwritten with zeros before the page is erased, since it may contain keys.
+    ///
+    /// # Safety
+    ///
+    /// Must ensure the ICC is disabled before calling this.
+    pub unsafe fn clear(&mut self) {
+        let flash = Flash::get();
+
+        // read count before erasing page
+        let status = Self::status_bytes(FLASH_ENTRY_EMPTY_MAGIC, self.next_write_count(), 0);
+
+        unsafe {
+            // safety: no references should be at this page, since no references are returned on get
+            flash
+                .secure_erase_page(self.address)
+                .expect("failed to erase flash page");
+
+            // safety: status resides within this flash page
+            flash
+                .write(self.status_address(), &status)
                 .expect("failed to write status to flash");
         }
     }
@@ -131,32 +281,42 @@ pub struct CompressedSubscriptionEntry {
     pub node_keys: [[u8; 32]; 128],
 }
 
+impl FlashPersistable for CompressedSubscriptionEntry {
+    const MAGIC: u32 = 0x11aa0055;
+    const VERSION: u32 = 0;
+}
+
+impl Zeroize for CompressedSubscriptionEntry {
+    fn zeroize(&mut self) {
+        bytes_of_mut(self).zeroize();
+    }
+}
+
 impl CompressedSubscriptionEntry {
     /// Returns the depth of the key node at the given `node_index`.
     fn node_depth(&self, node_index: usize) -> u8 {
         self.depths[node_index]
     }
 
-    /// Gets the inclusive end time of this subscription entry
-    fn end_time(&self) -> u64 {
-        let mut current_timestamp = self.start_time;
-        for i in 0..self.subtree_count as usize {
-            let depth = self.node_depth(i);
-
-            let lowest_timestamp = current_timestamp;
-            // prevent overflow when shift would be 64
-            let offset = if depth == 0 {
-                u64::MAX
-            } else {
-                (1 << (64 - depth)) - 1
-            };
-            let highest_timestamp = lowest_timestamp + offset;
-
-            current_timestamp = highest_timestamp.wrapping_add(1); // avoid a panic if highest_timestamp == u64::MAX
+    /// Gets the inclusive end time of this subscription entry,
+    /// or `None` if the depths do not describe a valid range of timestamps.
+    ///
+    /// Subtrees are contiguous, so this is just `start_time` plus the width of every subtree, minus 1.
+    /// This is `None` if there are no subtrees, any depth is bigger than 64,
+    /// or the subtrees would go past `u64::MAX`.
+    pub fn checked_end_time(&self) -> Option<u64> {
+        let depths = self.depths.get(..self.subtree_count as usize)?;
+        if depths.is_empty() || depths.iter().any(|&depth| depth > 64) {
+            return None;
         }
 
-        // if last timestamp was u64::MAX, last addition would wrap to 0, so we undo that wrap
-        current_timestamp.wrapping_sub(1)
+        // a depth 0 subtree alone is 2^64 wide, so sum in u128 to not overflow
+        let width: u128 = depths
+            .iter()
+            .map(|&depth| 1u128 << (64 - u32::from(depth)))
+            .sum();
+
+        u64::try_from(u128::from(self.start_time) + width - 1).ok()
     }
 
     /// Gets the subtree containing `timestamp`, or returns `None` if no such subtree exists in this subscription.
@@ -227,12 +387,18 @@ pub struct ChannelCache {
 }
 
 impl ChannelCache {
-    fn new(public_key: &[u8; 32]) -> Self {
-        ChannelCache {
-            public_key: VerifyingKey::from_bytes(public_key)
-                .expect("Invalid public key for subscription"),
+    /// Creates an empty cache for the channel of `subscription`,
+    /// or returns `None` if the subscription can't be used to decode anything.
+    ///
+    /// Subscriptions are checked when subscribing, but entries written before the checks existed
+    /// may have a public key which could not be parsed, or depths without a valid end time.
+    fn for_subscription(subscription: &CompressedSubscriptionEntry) -> Option<Self> {
+        subscription.checked_end_time()?;
+
+        Some(ChannelCache {
+            public_key: VerifyingKey::from_bytes(&subscription.public_key).ok()?,
             cache_entries: ArrayVec::new(),
-        }
+        })
     }
 }
 
@@ -242,10 +408,102 @@ struct ChannelInfo {
     flash_entry: FlashEntry<CompressedSubscriptionEntry>,
     /// Contains cached info about channel.
     ///
-    /// None if there is no subscription for channel.
+    /// None if there is no subscription for channel, or the subscription is quarantined.
     cache: Option<ChannelCache>,
 }
 
+/// What a subscription slot contains at boot, used to decide which slots to keep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    /// No subscription is stored in the slot.
+    Empty,
+    /// A subscription is stored, but can't be used, such as if it has an invalid public key
+    /// or was written by firmware with a different layout version.
+    Unusable,
+    /// A usable subscription is stored, written with sequence number `sequence`.
+    Subscribed { channel_id: u32, sequence: u32 },
+}
+
+impl SlotState {
+    /// Gets the state of a slot from the status of its flash entry.
+    ///
+    /// `usable` is the channel id and sequence number of the stored subscription, if it can be used.
+    fn from_entry(entry_status: FlashEntryStatus, usable: Option<(u32, u32)>) -> Self {
+        match entry_status {
+            FlashEntryStatus::Valid => match usable {
+                Some((channel_id, sequence)) => SlotState::Subscribed {
+                    channel_id,
+                    sequence,
+                },
+                None => SlotState::Unusable,
+            },
+            FlashEntryStatus::Incompatible { .. } => SlotState::Unusable,
+            FlashEntryStatus::Empty => SlotState::Empty,
+        }
+    }
+}
+
+/// What to do with a subscription slot at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BootAction {
+    /// Leave the slot as it is.
+    Keep,
+    /// Erase the subscription in the slot.
+    Clear,
+    /// Another slot has a newer copy of the subscription, so erase this older copy.
+    ClearStale,
+}
+
+/// Decides what to do with each subscription slot at boot.
+///
+/// Unusable subscriptions are cleared. A power loss while moving a subscription to another page
+/// can leave the channel on 2 pages, in which case the copy with the bigger sequence number is newer and kept,
+/// no matter which page it is on, and the older copy is cleared so its keys don't stay in flash.
+fn plan_boot_actions(slots: &[SlotState; MAX_SUBSCRIPTIONS]) -> [BootAction; MAX_SUBSCRIPTIONS] {
+    core::array::from_fn(|i| match slots[i] {
+        SlotState::Empty => BootAction::Keep,
+        SlotState::Unusable => BootAction::Clear,
+        SlotState::Subscribed {
+            channel_id,
+            sequence,
+        } => {
+            let superseded = slots.iter().enumerate().any(|(j, other)| match *other {
+                SlotState::Subscribed {
+                    channel_id: other_channel_id,
+                    sequence: other_sequence,
+                } if other_channel_id == channel_id => {
+                    // equal sequence numbers are only possible for pages written before sequence numbers existed,
+                    // which never had duplicates, so there is nothing better than the index to pick with
+                    other_sequence > sequence || (other_sequence == sequence && j < i)
+                }
+                _ => false,
+            });
+
+            if superseded {
+                BootAction::ClearStale
+            } else {
+                BootAction::Keep
+            }
+        }
+    })
+}
+
+/// Gets the sequence number for the first subscription written after boot.
+///
+/// Every write needs a bigger sequence number than any already in flash.
+/// Returns `None` if a slot already has the biggest sequence number, rather than wrapping around to a
+/// number that would make the next write look older than the copy it replaces.
+fn next_sequence_at_boot(slots: &[SlotState; MAX_SUBSCRIPTIONS]) -> Option<u32> {
+    slots
+        .iter()
+        .filter_map(|state| match *state {
+            SlotState::Subscribed { sequence, .. } => Some(sequence),
+            _ => None,
+        })
+        .max()
+        .map_or(Some(1), |sequence| sequence.checked_add(1))
+}
+
 impl ChannelInfo {
     /// Constructs new `ChannelInfo` object by reading from flash which may or may not contain subscription data.
     ///
@@ -258,30 +516,63 @@ impl ChannelInfo {
         let flash_entry: FlashEntry<CompressedSubscriptionEntry> =
             unsafe { FlashEntry::new(flash_data_addr) };
 
-        let cache = if let Some(subscription) = flash_entry.get() {
-            Some(ChannelCache::new(&subscription.public_key))
-        } else {
-            None
-        };
+        // unusable subscriptions get no cache, so they are cleared at boot
+        let cache = flash_entry.get().and_then(ChannelCache::for_subscription);
 
         ChannelInfo { flash_entry, cache }
     }
 
+    /// Gets what is stored in this slot, to decide what to do with it at boot.
+    ///
+    /// A subscription whose public key could not be parsed or whose depths overflow
+    /// can't be used to decode anything, so it is unusable.
+    fn boot_state(&self) -> SlotState {
+        let usable = match (self.flash_entry.get(), &self.cache) {
+            (Some(subscription), Some(_)) => {
+                Some((subscription.channel_id, self.flash_entry.sequence()))
+            }
+            _ => None,
+        };
+
+        SlotState::from_entry(self.flash_entry.entry_status(), usable)
+    }
+
+    /// Gets the subscription in this slot, or `None` if the slot is empty.
+    fn subscription(&self) -> Option<&CompressedSubscriptionEntry> {
+        self.flash_entry.get()
+    }
+
     /// Gets the channel id for this ChannelInfo, or `None` if it is not subscribed to any channel.
     fn channel_id(&self) -> Option<u32> {
-        Some(self.flash_entry.get()?.channel_id)
+        Some(self.subscription()?.channel_id)
     }
 
-    /// Updates the subscription for this channel cache
-    /// 
+    /// Updates the subscription for this channel cache, marking it with `sequence`.
+    ///
     /// # Safety
-    /// 
+    ///
+    /// Must ensure ICC is disabled before calling this function
+    unsafe fn set_subscription(
+        &mut self,
+        subscription: &CompressedSubscriptionEntry,
+        sequence: u32,
+    ) {
+        unsafe {
+            self.flash_entry.set(subscription, sequence);
+        }
+        self.cache = ChannelCache::for_subscription(subscription);
+    }
+
+    /// Removes the subscription from this channel info, leaving the slot empty.
+    ///
+    /// # Safety
+    ///
     /// Must ensure ICC is disabled before calling this function
-    unsafe fn set_subscription(&mut self, subscription: &CompressedSubscriptionEntry) {
+    unsafe fn clear_subscription(&mut self) {
         unsafe {
-            self.flash_entry.set(subscription);
+            self.flash_entry.clear();
         }
-        self.cache = Some(ChannelCache::new(&subscription.public_key));
+        self.cache = None;
     }
 }
 
@@ -289,6 +580,8 @@ impl ChannelInfo {
 pub enum DecoderContextError {
     #[error("Too many subscriptions!")]
     TooManySubscriptions,
+    #[error("Subscription sequence numbers used up")]
+    SequenceExhausted,
 }
 
 /// Format of information about channel sent back to tv host tools for list channels command.
@@ -300,25 +593,88 @@ pub struct DecoderChannelInfoResult {
     end_time: u64,
 }
 
+/// Status of one subscription slot, sent to host tools as a debug struct.
+///
+/// Only says where subscriptions are stored and how worn their flash pages are, no keys.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct SlotStatus {
+    /// Index of the slot in [`FLASH_DATA_ADDRS`]
+    pub slot: u32,
+    /// Channel of the subscription in the slot, or 0 if `subscribed` is 0
+    pub channel_id: u32,
+    /// Sequence number the slot was last written with, 0 if it has never held a subscription
+    pub sequence: u32,
+    /// Number of times the flash page of the slot has been written
+    pub write_count: u32,
+    /// 1 if the slot holds a usable subscription, 0 if it is empty
+    pub subscribed: u8,
+}
+
+/// Most recent failed decode, kept so host tools can ask for it later.
+///
+/// Only the error code and frame timestamp are stored, neither depend on any secrets.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeFailure {
+    /// Code of the error, from [`crate::DecoderError::code`].
+    pub code: u8,
+    /// Timestamp of the frame, or `None` if the frame was too short to contain one.
+    pub timestamp: Option<u64>,
+}
+
 /// Stores state of decoder.
 pub struct DecoderContext {
     /// Data for all subscriptions, indexed by channel id
     subscriptions: [ChannelInfo; MAX_SUBSCRIPTIONS],
-    /// Timestamp of last decoded frame (starts at 0)
-    pub last_decoded_timestamp: Option<u64>,
-    /// Verifying public key for subscriptions
-    pub subscription_public_key: VerifyingKey,
-    /// Verifying public key for frames on the emergency channel
-    pub emergency_channel_public_key: VerifyingKey,
+    /// Timestamps of decoded frames, used to reject replayed frames
+    pub decoded_timestamps: DecodedTimestamps,
+    /// Most recent failed decode, if any decode has failed since startup.
+    pub last_decode_failure: Option<DecodeFailure>,
+    /// Sequence number to mark the next subscription written to flash with.
+    ///
+    /// `None` if the biggest sequence number has been used, in which case no more subscriptions can be written.
+    next_sequence: Option<u32>,
+    /// Verifying public keys for subscriptions, the current key followed by keys from before rotations
+    pub subscription_public_keys: [VerifyingKey; SUBSCRIPTION_PUBLIC_KEY_COUNT],
+    /// Verifying public keys for frames on each emergency channel
+    pub emergency_channel_public_keys: [VerifyingKey; EMERGENCY_CHANNEL_COUNT],
     /// Instruction cache controller
     icc: Icc,
+    /// True random number generator, sampled by the entropy report
+    pub trng: Trng,
 }
 
 impl DecoderContext {
     /// Initialize decoder state and setup all necessary peripherals.
     pub fn new() -> Self {
-        let Peripherals { mut icc, mut mpu, .. } =
-            Peripherals::take().expect("could not initialize peripherals");
+        let Peripherals {
+            mut icc,
+            mut mpu,
+            mut trng,
+            rtc,
+            mut wdt,
+        } = Peripherals::take().expect("could not initialize peripherals");
+
+        let reset_cause = wdt.reset_cause();
+        if reset_cause != ResetCause::Other {
+            println!("last reset: {:?}", reset_cause);
+        }
+
+        // a wrong sysclock would throw off uart baud rate and flash timings.
+        // starting the rtc and measuring delays boot, so only debug builds check it
+        if cfg!(debug_assertions) {
+            match rtc.start() {
+                Ok(rtc) => {
+                    let (assumed, measured) =
+                        Gcr::with(|gcr| (gcr.get_sysclock_frequency(), gcr.measure_sysclock(&rtc)));
+                    println!("sysclock assumed: {assumed} Hz, measured: {measured:?} Hz");
+                }
+                Err(error) => println!("could not start rtc to measure sysclock: {error}"),
+            }
+        }
+
+        // refuse to run with a dead trng
+        trng.self_test().expect("trng failed health check");
 
         // lock all flash pages not used for storing subscription data
         for page_address in
@@ -382,7 +738,7 @@ impl DecoderContext {
             mpu.enable();
         }
 
-        let subscriptions = unsafe {
+        let mut subscriptions = unsafe {
             [
                 ChannelInfo::new(FLASH_DATA_ADDRS[0]),
                 ChannelInfo::new(FLASH_DATA_ADDRS[1]),
@@ -395,20 +751,67 @@ impl DecoderContext {
             ]
         };
 
-        let subscription_public_key = VerifyingKey::from_bytes(&SUBSCRIPTION_PUBLIC_KEY)
-            .expect("decoder loaded with invalid public key");
+        let slot_states = subscriptions.each_ref().map(ChannelInfo::boot_state);
+
+        let next_sequence = next_sequence_at_boot(&slot_states);
+        if next_sequence.is_none() {
+            println!(
+                "Warning: subscription sequence numbers used up, subscriptions can't be updated"
+            );
+        }
+
+        icc.disable();
+
+        for (channel_info, action) in subscriptions
+            .iter_mut()
+            .zip(plan_boot_actions(&slot_states))
+        {
+            match action {
+                BootAction::Keep => (),
+                // a corrupted public key would make the subscription unusable, so free up the slot
+                // rather than panicking and taking down the whole decoder
+                // subscriptions written by firmware with a different layout can't be read either,
+                // so they are also removed and need to be sent again
+                BootAction::Clear => {
+                    println!(
+                        "Removing subscription with invalid public key or incompatible version"
+                    );
+
+                    // safety: icc is disabled while clearing subscription
+                    unsafe {
+                        channel_info.clear_subscription();
+                    }
+                }
+                BootAction::ClearStale => {
+                    println!("Removing older copy of a moved subscription");
+
+                    // safety: icc is disabled while clearing subscription
+                    unsafe {
+                        channel_info.clear_subscription();
+                    }
+                }
+            }
+        }
+
+        let subscription_public_keys = SUBSCRIPTION_PUBLIC_KEYS.map(|public_key| {
+            VerifyingKey::from_bytes(&public_key).expect("decoder loaded with invalid public key")
+        });
 
-        let emergency_channel_public_key = VerifyingKey::from_bytes(&CHANNEL0_PUBLIC_KEY)
-            .expect("decoder loaded with invaid public key");
+        let emergency_channel_public_keys = EMERGENCY_CHANNEL_PUBLIC_KEYS.map(|public_key| {
+            VerifyingKey::from_bytes(&public_key).expect("decoder loaded with invaid public key")
+        });
 
         icc.enable();
 
         DecoderContext {
             subscriptions,
-            last_decoded_timestamp: None,
-            subscription_public_key,
-            emergency_channel_public_key,
+            decoded_timestamps: DecodedTimestamps::new(),
+            last_decode_failure: None,
+            next_sequence,
+            subscription_public_keys,
+            emergency_channel_public_keys,
             icc,
+            trng,
         }
     }
 
@@ -418,10 +821,43 @@ impl DecoderContext {
         )
     }
 
-    fn find_empty_channel_info(&mut self) -> Option<&mut ChannelInfo> {
+    /// Finds the index of the empty slot whose flash page has been written the least.
+    fn find_least_written_empty_slot(&self) -> Option<usize> {
         self.subscriptions
-            .iter_mut()
-            .find(|channel_info| channel_info.channel_id().is_none())
+            .iter()
+            .enumerate()
+            .filter(|(_, channel_info)| channel_info.channel_id().is_none())
+            .min_by_key(|(_, channel_info)| channel_info.flash_entry.write_count())
+            .map(|(i, _)| i)
+    }
+
+    /// Returns the number of subscription slots currently holding a subscription.
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions
+            .iter()
+            .filter(|channel_info| channel_info.channel_id().is_some())
+            .count()
+    }
+
+    /// Returns the status of every subscription slot, for debugging.
+    pub fn slot_statuses(&self) -> [SlotStatus; MAX_SUBSCRIPTIONS] {
+        core::array::from_fn(|slot| {
+            let channel_info = &self.subscriptions[slot];
+            let channel_id = channel_info.channel_id();
+
+            SlotStatus {
+                slot: slot as u32,
+                channel_id: channel_id.unwrap_or(0),
+                sequence: channel_info.flash_entry.sequence(),
+                write_count: channel_info.flash_entry.write_count(),
+                subscribed: channel_id.is_some().into(),
+            }
+        })
+    }
+
+    /// Returns the number of subscription slots which are still free for new channels.
+    pub fn free_slots(&self) -> usize {
+        MAX_SUBSCRIPTIONS - self.subscription_count()
     }
 
     /// Retreives both nonvalatile and volatile cached information about a subscription on the given `channel_id`.
@@ -431,41 +867,93 @@ impl DecoderContext {
         &mut self,
         channel_id: u32,
     ) -> Option<(&CompressedSubscriptionEntry, &mut ChannelCache)> {
-        let ChannelInfo { flash_entry, cache } = self.get_channel_info_for_id(channel_id)?;
+        let ChannelInfo {
+            flash_entry, cache, ..
+        } = self.get_channel_info_for_id(channel_id)?;
 
         Some((flash_entry.get().unwrap(), cache.as_mut().unwrap()))
     }
 
+    /// Derives the keys for `timestamp` on `channel_id` ahead of time and leaves them in the channel cache,
+    /// so the first frame decoded with that timestamp does not have to derive any keys.
+    pub fn warm_cache(&mut self, channel_id: u32, timestamp: u64) -> Result<(), DecoderError> {
+        let (subscription, cache) = self
+            .get_subscription_for_channel(channel_id)
+            .ok_or(DecoderError::InvalidSubscription)?;
+
+        // only the cache is wanted, the key itself shouldn't stay on the stack
+        let _key = Zeroizing::new(derive_decoder_key_for_timestamp(
+            subscription,
+            cache,
+            timestamp,
+        )?);
+
+        Ok(())
+    }
+
     /// Updates subscription information using provided `subscription`.
     ///
     /// If a subscription with the same channel id already exists, it is overwritten.
     /// If no such subscription exists, a new slot is used to store the subscription.
     /// If all 8 subscription slots have been taken, `update_subscription` will return an error.
+    ///
+    /// To spread out flash wear, the subscription is written to the least written empty page,
+    /// and an existing subscription for the channel is moved there if that page is less worn than its current one.
+    /// After a move, the old page is cleared so the old keys don't stay in flash.
+    ///
+    /// Returns an error without touching flash if the sequence numbers are used up.
     pub fn update_subscription(
         &mut self,
         subscription: &CompressedSubscriptionEntry,
     ) -> Result<(), DecoderContextError> {
-        self.icc.disable();
+        let current_slot = self.subscriptions.iter().position(|channel_info| {
+            matches!(channel_info.channel_id(), Some(cid) if cid == subscription.channel_id)
+        });
 
-        let result = if let Some(channel_info) = self.get_channel_info_for_id(subscription.channel_id) {
-            // safety: icc is disabled while setting subscription
-            unsafe {
-                channel_info.set_subscription(subscription);
+        // check before touching flash so a new channel with no free slots fails early
+        if current_slot.is_none() && self.free_slots() == 0 {
+            return Err(DecoderContextError::TooManySubscriptions);
+        }
+
+        let sequence = self
+            .next_sequence
+            .ok_or(DecoderContextError::SequenceExhausted)?;
+
+        let target_slot = match (current_slot, self.find_least_written_empty_slot()) {
+            (Some(current), Some(empty))
+                if self.subscriptions[empty].flash_entry.write_count()
+                    < self.subscriptions[current].flash_entry.write_count() =>
+            {
+                empty
             }
-            Ok(())
-        } else if let Some(channel_info) = self.find_empty_channel_info() {
-            // safety: icc is disabled while setting subscription
+            (Some(current), _) => current,
+            (None, Some(empty)) => empty,
+            (None, None) => unreachable!("free slot was checked for above"),
+        };
+
+        self.icc.disable();
+
+        // safety: icc is disabled while setting subscription
+        unsafe {
+            self.subscriptions[target_slot].set_subscription(subscription, sequence);
+        }
+        self.next_sequence = sequence.checked_add(1);
+
+        // the old page is only cleared once the new one is written, so a power loss never loses the subscription
+        // after a reboot, the new page is picked over the old one by its sequence number and the old one is cleared
+        if let Some(current) = current_slot.filter(|&current| current != target_slot) {
+            // safety: icc is disabled while clearing subscription
             unsafe {
-                channel_info.set_subscription(subscription);
+                self.subscriptions[current].clear_subscription();
             }
-            Ok(())
-        } else {
-            Err(DecoderContextError::TooManySubscriptions)
-        };
+        }
 
         self.icc.enable();
 
-        result
+        #[cfg(feature = "flash-timing")]
+        println!("flash timings (cycles): {:?}", Flash::get().timings());
+
+        Ok(())
     }
 
     /// Returns a list of info about all subscribed channels.
@@ -475,11 +963,16 @@ impl DecoderContext {
         let mut out = ArrayVec::new();
 
         for channel_info in &self.subscriptions {
-            if let Some(subscription) = channel_info.flash_entry.get() {
+            let Some(subscription) = channel_info.subscription() else {
+                continue;
+            };
+
+            // invalid entries are cleared at boot, so this should not be skipped
+            if let Some(end_time) = subscription.checked_end_time() {
                 out.push(DecoderChannelInfoResult {
                     channel_id: subscription.channel_id,
                     start_time: subscription.start_time,
-                    end_time: subscription.end_time(),
+                    end_time,
                 });
             }
         }
@@ -487,3 +980,276 @@ impl DecoderContext {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rand_chacha::ChaCha8Rng;
+    use rand_core::{RngCore, SeedableRng};
+
+    use super::*;
+
+    const EMPTY_SLOTS: [SlotState; MAX_SUBSCRIPTIONS] = [SlotState::Empty; MAX_SUBSCRIPTIONS];
+
+    fn subscribed(channel_id: u32, sequence: u32) -> SlotState {
+        SlotState::Subscribed {
+            channel_id,
+            sequence,
+        }
+    }
+
+    /// Walks every subtree to find the end time, the way it was done before [`CompressedSubscriptionEntry::checked_end_time`].
+    fn reference_end_time(subscription: &CompressedSubscriptionEntry) -> Option<u64> {
+        let mut next_timestamp = Some(subscription.start_time);
+        let mut end_time = None;
+        for &depth in subscription
+            .depths
+            .get(..subscription.subtree_count as usize)?
+        {
+            if depth > 64 {
+                return None;
+            }
+
+            let lowest_timestamp = next_timestamp?;
+            let highest_timestamp =
+                lowest_timestamp.checked_add(((1u128 << (64 - depth)) - 1) as u64)?;
+
+            end_time = Some(highest_timestamp);
+            next_timestamp = highest_timestamp.checked_add(1);
+        }
+
+        end_time
+    }
+
+    #[test]
+    fn end_time_matches_linear_walk() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        for _ in 0..10_000 {
+            let mut subscription: CompressedSubscriptionEntry = Zeroable::zeroed();
+            // bias towards the top of the range and shallow trees, so both results are common
+            subscription.start_time = rng.next_u64() >> (rng.next_u32() % 65).min(63);
+            subscription.subtree_count = rng.next_u32() % 6;
+            for depth in &mut subscription.depths[..subscription.subtree_count as usize] {
+                *depth = match rng.next_u32() % 8 {
+                    0 => 0,
+                    1 => 65 + (rng.next_u32() % 3) as u8,
+                    _ => (rng.next_u32() % 65) as u8,
+                };
+            }
+
+            assert_eq!(
+                subscription.checked_end_time(),
+                reference_end_time(&subscription),
+                "start {} depths {:?}",
+                subscription.start_time,
+                &subscription.depths[..subscription.subtree_count as usize],
+            );
+        }
+    }
+
+    #[test]
+    fn end_time_rejects_out_of_range_subtree_count() {
+        let mut subscription: CompressedSubscriptionEntry = Zeroable::zeroed();
+        subscription.depths = [64; 128];
+
+        subscription.subtree_count = 128;
+        assert_eq!(subscription.checked_end_time(), Some(127));
+
+        subscription.subtree_count = 129;
+        assert_eq!(subscription.checked_end_time(), None);
+    }
+
+    #[test]
+    fn end_time_can_reach_but_not_pass_max_timestamp() {
+        let mut subscription: CompressedSubscriptionEntry = Zeroable::zeroed();
+        for (start_time, depths, end_time) in [
+            (0, &[0][..], Some(u64::MAX)),
+            (0, &[1, 1], Some(u64::MAX)),
+            (0, &[1, 1, 64], None),
+            (1, &[0], None),
+            (u64::MAX, &[64], Some(u64::MAX)),
+            (u64::MAX, &[63], None),
+        ] {
+            subscription.start_time = start_time;
+            subscription.subtree_count = depths.len() as u32;
+            subscription.depths[..depths.len()].copy_from_slice(depths);
+            assert_eq!(subscription.checked_end_time(), end_time);
+            assert_eq!(reference_end_time(&subscription), end_time);
+        }
+    }
+
+    /// A subscription which is valid apart from what each test breaks.
+    fn usable_subscription() -> CompressedSubscriptionEntry {
+        let mut subscription: CompressedSubscriptionEntry = Zeroable::zeroed();
+        subscription.public_key = SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes();
+        subscription.subtree_count = 1;
+        subscription.depths[0] = 60;
+        subscription
+    }
+
+    #[test]
+    fn usable_subscription_gets_cache() {
+        assert!(ChannelCache::for_subscription(&usable_subscription()).is_some());
+    }
+
+    #[test]
+    fn invalid_public_key_gets_no_cache() {
+        let mut subscription = usable_subscription();
+        // y = 2 is not the y coordinate of any point on the curve
+        subscription.public_key = [0; 32];
+        subscription.public_key[0] = 2;
+        assert!(VerifyingKey::from_bytes(&subscription.public_key).is_err());
+
+        assert!(ChannelCache::for_subscription(&subscription).is_none());
+    }
+
+    #[test]
+    fn invalid_depths_get_no_cache() {
+        let mut subscription = usable_subscription();
+        subscription.start_time = u64::MAX;
+        assert!(ChannelCache::for_subscription(&subscription).is_none());
+
+        let mut subscription = usable_subscription();
+        subscription.depths[0] = 65;
+        assert!(ChannelCache::for_subscription(&subscription).is_none());
+    }
+
+    #[test]
+    fn power_loss_after_move_keeps_newer_copy() {
+        // newer copy on a later page
+        let mut slots = EMPTY_SLOTS;
+        slots[0] = subscribed(1, 3);
+        slots[5] = subscribed(1, 4);
+
+        let actions = plan_boot_actions(&slots);
+        assert_eq!(actions[0], BootAction::ClearStale);
+        assert_eq!(actions[5], BootAction::Keep);
+
+        // newer copy on an earlier page
+        let mut slots = EMPTY_SLOTS;
+        slots[2] = subscribed(1, 7);
+        slots[6] = subscribed(1, 6);
+
+        let actions = plan_boot_actions(&slots);
+        assert_eq!(actions[2], BootAction::Keep);
+        assert_eq!(actions[6], BootAction::ClearStale);
+    }
+
+    #[test]
+    fn next_sequence_is_after_every_stored_sequence() {
+        assert_eq!(next_sequence_at_boot(&EMPTY_SLOTS), Some(1));
+
+        let mut slots = EMPTY_SLOTS;
+        slots[1] = subscribed(1, 7);
+        slots[4] = subscribed(2, 3);
+        slots[6] = SlotState::Unusable;
+        assert_eq!(next_sequence_at_boot(&slots), Some(8));
+    }
+
+    #[test]
+    fn next_sequence_does_not_wrap() {
+        let mut slots = EMPTY_SLOTS;
+        slots[0] = subscribed(1, u32::MAX - 1);
+        assert_eq!(next_sequence_at_boot(&slots), Some(u32::MAX));
+
+        // wrapping to 0 would make the next write lose to the copy it replaces
+        slots[3] = subscribed(2, u32::MAX);
+        assert_eq!(next_sequence_at_boot(&slots), None);
+    }
+
+    #[test]
+    fn power_loss_before_move_finished_keeps_old_copy() {
+        // new page was erased, but its status was never written
+        let mut slots = EMPTY_SLOTS;
+        slots[1] = subscribed(1, 3);
+
+        assert_eq!(
+            plan_boot_actions(&slots),
+            [BootAction::Keep; MAX_SUBSCRIPTIONS]
+        );
+    }
+
+    #[test]
+    fn boot_keeps_every_channel_once() {
+        let mut slots = EMPTY_SLOTS;
+        slots[0] = subscribed(1, 1);
+        slots[1] = subscribed(2, 2);
+        slots[2] = subscribed(1, 5);
+        slots[3] = subscribed(3, 3);
+        slots[4] = subscribed(1, 4);
+
+        let actions = plan_boot_actions(&slots);
+        assert_eq!(
+            actions,
+            [
+                BootAction::ClearStale,
+                BootAction::Keep,
+                BootAction::Keep,
+                BootAction::Keep,
+                BootAction::ClearStale,
+                BootAction::Keep,
+                BootAction::Keep,
+                BootAction::Keep,
+            ]
+        );
+    }
+
+    #[test]
+    fn unusable_slots_are_cleared() {
+        let mut slots = EMPTY_SLOTS;
+        slots[3] = SlotState::Unusable;
+        slots[4] = subscribed(1, 1);
+
+        let actions = plan_boot_actions(&slots);
+        assert_eq!(actions[3], BootAction::Clear);
+        assert_eq!(actions[4], BootAction::Keep);
+    }
+
+    #[test]
+    fn mismatched_version_is_cleared_at_boot() {
+        type Entry = FlashEntry<CompressedSubscriptionEntry>;
+        let magic = CompressedSubscriptionEntry::MAGIC;
+        let version = CompressedSubscriptionEntry::VERSION;
+
+        assert_eq!(
+            Entry::entry_status_from(magic, version),
+            FlashEntryStatus::Valid
+        );
+        for other_version in [version + 1, u32::MAX] {
+            assert_eq!(
+                Entry::entry_status_from(magic, other_version),
+                FlashEntryStatus::Incompatible {
+                    version: other_version
+                }
+            );
+        }
+        // the version is not checked if the entry is empty
+        assert_eq!(
+            Entry::entry_status_from(FLASH_ENTRY_EMPTY_MAGIC, version + 1),
+            FlashEntryStatus::Empty
+        );
+
+        let mut slots = EMPTY_SLOTS;
+        slots[0] = SlotState::from_entry(
+            Entry::entry_status_from(magic, version + 1),
+            // an incompatible entry is never read, even if it would have parsed
+            Some((1, 2)),
+        );
+        slots[1] = SlotState::from_entry(Entry::entry_status_from(magic, version), Some((1, 1)));
+        slots[2] = SlotState::from_entry(Entry::entry_status_from(magic, version), None);
+        slots[3] = SlotState::from_entry(Entry::entry_status_from(0xffff_ffff, version), None);
+
+        assert_eq!(slots[0], SlotState::Unusable);
+        assert_eq!(slots[1], subscribed(1, 1));
+        assert_eq!(slots[2], SlotState::Unusable);
+        assert_eq!(slots[3], SlotState::Empty);
+
+        let actions = plan_boot_actions(&slots);
+        // the incompatible copy does not supersede the valid one, even with a bigger sequence number
+        assert_eq!(actions[0], BootAction::Clear);
+        assert_eq!(actions[1], BootAction::Keep);
+        assert_eq!(actions[2], BootAction::Clear);
+        assert_eq!(actions[3], BootAction::Keep);
+    }
+}
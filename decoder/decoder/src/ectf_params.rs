@@ -2,5 +2,26 @@
 
 include!(concat!(env!("OUT_DIR"), "/ectf_params.rs"));
 
-pub const MAX_SUBSCRIPTIONS: usize = 8;
-pub const EMERGENCY_CHANNEL_ID: u32 = 0;
+/// Each subscription is stored on its own flash page, so this is set by the build script.
+pub const MAX_SUBSCRIPTIONS: usize = FLASH_DATA_ADDRS.len();
+
+/// Returns the index of `channel_id` in the `EMERGENCY_CHANNEL_*` arrays,
+/// or `None` if it is not an emergency channel.
+pub fn emergency_channel_index(channel_id: u32) -> Option<usize> {
+    EMERGENCY_CHANNEL_IDS
+        .iter()
+        .position(|&id| id == channel_id)
+}
+
+/// Number of writes to a flash page after which a warning is printed.
+///
+/// Max78000 flash is rated for 10,000 erase cycles, so warn a bit before that.
+pub const FLASH_WRITE_COUNT_WARNING: u32 = 9000;
+
+/// Number of timestamps before the newest decoded frame which can still be decoded, if not decoded already.
+///
+/// Must be 0 for security requirement 3 to hold, which is the default.
+/// Can be at most 64, since decoded timestamps in the window are tracked with a `u64` bitmap.
+pub const REPLAY_WINDOW: u64 = 0;
+#[allow(clippy::absurd_extreme_comparisons)]
+const _: () = assert!(REPLAY_WINDOW <= 64);
@@ -1,16 +1,19 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// unit tests run on the host without the firmware entry point, so most of the firmware is unused there
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 use bytemuck::PodCastError;
 use bytemuck::{checked::CheckedCastError, must_cast_slice};
 use core::panic::PanicInfo;
 use cortex_m_rt::entry;
 use decoder_context::{DecoderContext, DecoderContextError};
-use max78000_hal::led::{led_off, led_on, Led};
+use max78000_hal::led::{set_status, Status};
+use max78000_hal::uart::uart;
 use max78000_hal::HalError;
 use message::{Message, MessageError, Opcode};
 use thiserror_no_std::Error;
-use utils::{write_error, Cursor, CursorError};
+use utils::{flush_debug_log, send_debug_struct, write_error, Cursor, CursorError};
 
 mod crypto;
 mod decode;
@@ -46,6 +49,28 @@ pub enum DecoderError {
     DecoderContextError(#[from] DecoderContextError),
 }
 
+/// Error code reported to the host tools when the decoder panics.
+pub const PANIC_ERROR_CODE: u8 = 0xff;
+
+impl DecoderError {
+    /// Machine readable code sent to host tools at the start of an error packet.
+    pub fn code(&self) -> u8 {
+        match self {
+            DecoderError::HalError(_) => 0x01,
+            DecoderError::CastError(_) => 0x02,
+            DecoderError::PodCastError(_) => 0x03,
+            DecoderError::SuspiciousActivity => 0x04,
+            DecoderError::NoTimestampFound => 0x05,
+            DecoderError::NonMonotonicTimestamp => 0x06,
+            DecoderError::InvalidEncoderPayload => 0x07,
+            DecoderError::InvalidSubscription => 0x08,
+            DecoderError::MessagingError(_) => 0x09,
+            DecoderError::CursorError(_) => 0x0a,
+            DecoderError::DecoderContextError(_) => 0x0b,
+        }
+    }
+}
+
 /// Performs the list channels functionality required by host tools.
 fn list_channels(context: &mut DecoderContext) -> Result<(), DecoderError> {
     let channel_info = context.list_channels();
@@ -65,33 +90,146 @@ fn list_channels(context: &mut DecoderContext) -> Result<(), DecoderError> {
     Ok(())
 }
 
+/// Reports the most recent decode failure to the host tools.
+///
+/// Body is empty if no decode has failed, otherwise it is the 1 byte error code,
+/// followed by the 8 byte frame timestamp if the frame had one.
+fn report_last_error(context: &mut DecoderContext) -> Result<(), DecoderError> {
+    let mut data = [0; 9];
+    let mut data_cursor = Cursor::new(&mut data);
+
+    if let Some(failure) = context.last_decode_failure {
+        data_cursor.read_from(&[failure.code])?;
+
+        if let Some(timestamp) = failure.timestamp {
+            data_cursor.read_from(&timestamp.to_le_bytes())?;
+        }
+    }
+    let data = data_cursor.written();
+
+    let response = Message::from_data(Opcode::LastError, data);
+    response.write()?;
+
+    Ok(())
+}
+
+/// Reports how many subscription slots are used to the host tools.
+///
+/// Body is the number of subscriptions followed by the number of free slots, both as little endian u32s,
+/// so host tools can warn before sending a subscription which would not fit.
+fn report_info(context: &mut DecoderContext) -> Result<(), DecoderError> {
+    let mut data = [0; 8];
+    let mut data_cursor = Cursor::new(&mut data);
+    data_cursor.read_from(&(context.subscription_count() as u32).to_le_bytes())?;
+    data_cursor.read_from(&(context.free_slots() as u32).to_le_bytes())?;
+
+    let response = Message::from_data(Opcode::Info, data_cursor.written());
+    response.write()?;
+
+    Ok(())
+}
+
+/// Sends buffered debug output to the host tools as debug packets, then the status of every
+/// subscription slot as debug structs, followed by an empty log response.
+fn send_log(context: &DecoderContext) -> Result<(), DecoderError> {
+    flush_debug_log()?;
+
+    for slot_status in context.slot_statuses() {
+        send_debug_struct(&slot_status)?;
+    }
+
+    Message::send_data(Opcode::Log, &[])?;
+
+    Ok(())
+}
+
+/// Samples the trng and reports statistics about its output, without sending any sampled bytes.
+///
+/// The body may contain the number of bytes to sample as a little endian u32, otherwise 4096 are sampled.
+/// A summary is printed, and the response body is 1 byte which is 1 if the output
+/// passed a chi-square uniformity check, followed by the chi-square statistic as a little endian u64
+/// and the estimated min entropy per byte as little endian 16.16 fixed point bits.
+#[cfg(debug_assertions)]
+fn report_entropy(context: &mut DecoderContext, body: &[u8]) -> Result<(), DecoderError> {
+    use crate::println;
+    use max78000_hal::trng::MAX_ENTROPY_SAMPLES;
+
+    let sample_count = match body {
+        [] => 4096,
+        _ => u32::from_le_bytes(
+            body.try_into()
+                .map_err(|_| DecoderError::InvalidEncoderPayload)?,
+        ),
+    };
+    if !(1..=MAX_ENTROPY_SAMPLES).contains(&sample_count) {
+        return Err(DecoderError::InvalidEncoderPayload);
+    }
+
+    let report = context.trng.measure_entropy(sample_count);
+    let passed = report.passes_chi_square();
+
+    println!(
+        "trng: {} samples, chi-square {}, min entropy {}.{:03} bits/byte, {}",
+        report.sample_count,
+        report.chi_square,
+        report.min_entropy >> 16,
+        (u64::from(report.min_entropy & 0xffff) * 1000) >> 16,
+        if passed { "passed" } else { "FAILED" },
+    );
+    flush_debug_log()?;
+
+    let mut data = [0; 13];
+    let mut data_cursor = Cursor::new(&mut data);
+    data_cursor.read_from(&[u8::from(passed)])?;
+    data_cursor.read_from(&report.chi_square.to_le_bytes())?;
+    data_cursor.read_from(&report.min_entropy.to_le_bytes())?;
+
+    let response = Message::from_data(Opcode::Entropy, data_cursor.written());
+    response.write()?;
+
+    Ok(())
+}
+
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     let mut context = DecoderContext::new();
-    led_on(Led::Green);
+    set_status(Status::WaitingForCommand);
 
     loop {
+        // sleep while waiting for the host tools, instead of spinning on the uart
+        uart().sleep_until_readable();
+
         if let Ok(mut message) = Message::read() {
+            set_status(Status::Processing);
+
             // println!("got message: {:?}", message.opcode);
             let result = match message.opcode {
                 Opcode::List => list_channels(&mut context),
                 Opcode::Subscribe => subscribe::subscribe(&mut context, message.data_mut()),
                 Opcode::Decode => decode::decode(&mut context, message.data_mut()),
+                Opcode::LastError => report_last_error(&mut context),
+                Opcode::Log => send_log(&context),
+                Opcode::Info => report_info(&mut context),
+                #[cfg(debug_assertions)]
+                Opcode::Entropy => report_entropy(&mut context, message.data_mut()),
                 _ => Ok(()),
             };
 
             if let Err(error) = result {
-                write_error(&error).expect("Failed to report error");
+                write_error(error.code(), &error).expect("Failed to report error");
             }
+
+            set_status(Status::WaitingForCommand);
         }
     }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    led_on(Led::Red);
-    led_off(Led::Blue);
-    led_off(Led::Green);
-    let _ = write_error(info);
+    set_status(Status::Error);
+    let _ = flush_debug_log();
+    let _ = write_error(PANIC_ERROR_CODE, info);
     loop {}
 }
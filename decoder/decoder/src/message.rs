@@ -26,6 +26,8 @@ class Opcode(IntEnum):
     DEBUG = 0x47  # G
     ERROR = 0x45  # E
 */
+// LastError (0x52, R), Log (0x4f, O), Entropy (0x54, T), and Info (0x49, I) are not used by the host tools,
+// they are only for diagnostics
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Opcode {
     Decode,
@@ -34,6 +36,10 @@ pub enum Opcode {
     Ack,
     Debug,
     Error,
+    LastError,
+    Log,
+    Entropy,
+    Info,
 }
 
 impl TryFrom<u8> for Opcode {
@@ -46,6 +52,10 @@ impl TryFrom<u8> for Opcode {
             0x41 => Ok(Self::Ack),
             0x47 => Ok(Self::Debug),
             0x45 => Ok(Self::Error),
+            0x52 => Ok(Self::LastError),
+            0x4f => Ok(Self::Log),
+            0x54 => Ok(Self::Entropy),
+            0x49 => Ok(Self::Info),
             _ => Err(()),
         }
     }
@@ -60,6 +70,10 @@ impl From<Opcode> for u8 {
             Opcode::Ack => 0x41,
             Opcode::Debug => 0x47,
             Opcode::Error => 0x45,
+            Opcode::LastError => 0x52,
+            Opcode::Log => 0x4f,
+            Opcode::Entropy => 0x54,
+            Opcode::Info => 0x49,
         }
     }
 }
@@ -1,30 +1,53 @@
 use bytemuck::{AnyBitPattern, NoUninit, Pod, Zeroable};
+use ed25519_dalek::VerifyingKey;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::crypto::get_decoder_payload_associated_data;
 use crate::decoder_context::CompressedSubscriptionEntry;
-use crate::ectf_params::{DECODER_ID, EMERGENCY_CHANNEL_ID, SUBSCRIPTION_ENC_KEY};
+use crate::ectf_params::{emergency_channel_index, DECODER_ID, SUBSCRIPTION_ENC_KEY};
 use crate::message::{Message, Opcode};
 use crate::utils::{Cursor, CursorError};
 use crate::{crypto::decrypt_decoder_payload, decoder_context::DecoderContext, DecoderError};
 
+/// Maximum number of subtrees in a subscription, which is how many fit in a [`CompressedSubscriptionEntry`].
+const MAX_SUBTREES: usize = 128;
+
 /// Parses subscription entry from subscription data plaintext.
+///
+/// Returns an error instead of panicking if the plaintext is malformed,
+/// so a bad subscription can't take down the decoder.
+///
+/// A subscription with no subtrees is rejected. It covers no timestamps and has no end time to list,
+/// and the host tools never generate one, since a subscription always covers at least its start time.
 fn read_subscription(data: &[u8]) -> Result<CompressedSubscriptionEntry, DecoderError> {
     let mut data_cursor = Cursor::new(data);
 
     let public_key: [u8; 32] = read_value(&mut data_cursor)?;
+    if VerifyingKey::from_bytes(&public_key).is_err() {
+        return Err(DecoderError::InvalidSubscription);
+    }
 
     let start_time: u64 = read_value(&mut data_cursor)?;
 
+    // emergency channels don't need subscriptions, and their keys are hardcoded
     let channel_id: u32 = read_value(&mut data_cursor)?;
-    assert!(channel_id != EMERGENCY_CHANNEL_ID);
+    if emergency_channel_index(channel_id).is_some() {
+        return Err(DecoderError::InvalidSubscription);
+    }
 
-    let subtree_count = u32::from(read_value::<u8>(&mut data_cursor)?);
-    assert!(subtree_count <= 128);
+    // the subtree count says how long the rest of the subscription is,
+    // so look at it to check all the depths and keys are there before reading any of them
+    let subtree_count = u32::from(data_cursor.peek(1)?[0]);
+    if subtree_count > MAX_SUBTREES as u32 {
+        return Err(DecoderError::InvalidSubscription);
+    }
+    data_cursor.peek(1 + subtree_count as usize * (1 + size_of::<[u8; 32]>()))?;
+    data_cursor.offset += 1;
 
-    let mut depths = [0u8; 128];
+    let mut depths = [0u8; MAX_SUBTREES];
     data_cursor.read_into(&mut depths[..subtree_count as usize])?;
 
-    let mut node_keys = [[0u8; 32]; 128];
+    let mut node_keys = [[0u8; 32]; MAX_SUBTREES];
     data_cursor.read_into(bytemuck::cast_slice_mut(
         &mut node_keys[..subtree_count as usize],
     ))?;
@@ -41,6 +64,13 @@ fn read_subscription(data: &[u8]) -> Result<CompressedSubscriptionEntry, Decoder
         node_keys,
     };
 
+    // reject depths which don't fit in the timestamp range,
+    // since finding subtrees assumes they don't overflow
+    // an empty subscription has no end time, so it is rejected here too
+    if subscription.checked_end_time().is_none() {
+        return Err(DecoderError::InvalidSubscription);
+    }
+
     Ok(subscription)
 }
 
@@ -65,18 +95,29 @@ pub fn subscribe(
         return Err(DecoderError::InvalidSubscription);
     }
 
-    let subscription_public_key = &context.subscription_public_key;
-
     let subscription_data = decrypt_decoder_payload(
         subscribe_data,
         size_of::<SubscriptionAssociatedData>(),
         &SUBSCRIPTION_ENC_KEY,
-        subscription_public_key,
+        &context.subscription_public_keys,
     )?;
-    let entry = read_subscription(subscription_data)?;
+    let entry = read_subscription(subscription_data).map(Zeroizing::new);
+
+    // plaintext contains the subscription node keys, so wipe it from the message buffer
+    // whether or not it parsed
+    subscribe_data.zeroize();
 
+    let entry = entry?;
     context.update_subscription(&entry)?;
 
+    // the next frame is probably right after the last one decoded, or at the start of the subscription
+    let next_timestamp = context
+        .decoded_timestamps
+        .last()
+        .map_or(entry.start_time, |last| last.max(entry.start_time));
+    // best effort, decoding will derive the keys anyway if the guess is outside the subscription
+    let _ = context.warm_cache(entry.channel_id, next_timestamp);
+
     Message::send_data(Opcode::Subscribe, &[])?;
 
     Ok(())
@@ -91,3 +132,144 @@ where
     cursor.read_into(bytemuck::bytes_of_mut(&mut data))?;
     Ok(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    /// Builds subscription plaintext for `channel_id` starting at `start_time`, with a subtree for every depth.
+    fn encode_subscription(channel_id: u32, start_time: u64, depths: &[u8]) -> Vec<u8> {
+        let public_key = SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes();
+
+        let mut data = public_key.to_vec();
+        data.extend(start_time.to_le_bytes());
+        data.extend(channel_id.to_le_bytes());
+        data.push(depths.len() as u8);
+        data.extend(depths);
+        for i in 0..depths.len() {
+            data.extend([i as u8; 32]);
+        }
+        data
+    }
+
+    fn is_invalid_subscription<T>(result: Result<T, DecoderError>) -> bool {
+        matches!(result, Err(DecoderError::InvalidSubscription))
+    }
+
+    #[test]
+    fn max_subtree_count() {
+        let subscription = read_subscription(&encode_subscription(1, 0, &[64; 128])).unwrap();
+        assert_eq!(subscription.subtree_count, 128);
+        assert_eq!(subscription.node_keys[127], [127; 32]);
+
+        // the count is checked before reading any depths
+        let mut data = encode_subscription(1, 0, &[64; 128]);
+        data[44] = 129;
+        assert!(is_invalid_subscription(read_subscription(&data)));
+    }
+
+    #[test]
+    fn no_subtrees() {
+        // nothing can be decoded with an empty subscription, so it is rejected rather than stored
+        for start_time in [0, 1000, u64::MAX] {
+            let data = encode_subscription(1, start_time, &[]);
+            // public key, start time, channel id, and count only
+            assert_eq!(data.len(), 32 + 8 + 4 + 1);
+            assert!(is_invalid_subscription(read_subscription(&data)));
+        }
+
+        // a single subtree covering only the start time is the smallest accepted subscription
+        let subscription = read_subscription(&encode_subscription(1, 1000, &[64])).unwrap();
+        assert_eq!(subscription.checked_end_time(), Some(1000));
+    }
+
+    #[test]
+    fn emergency_channel() {
+        assert!(is_invalid_subscription(read_subscription(
+            &encode_subscription(0, 0, &[64])
+        )));
+        assert!(read_subscription(&encode_subscription(1, 0, &[64])).is_ok());
+    }
+
+    #[test]
+    fn invalid_public_key() {
+        let mut data = encode_subscription(1, 0, &[64]);
+        // y = 2 is not the y coordinate of any point on the curve
+        data[..32].fill(0);
+        data[0] = 2;
+        assert!(is_invalid_subscription(read_subscription(&data)));
+    }
+
+    #[test]
+    fn truncated_subscription() {
+        let data = encode_subscription(1, 0, &[63, 64]);
+        assert!(read_subscription(&data).is_ok());
+
+        // one byte short of each field, down to an empty plaintext
+        for len in (0..data.len()).rev() {
+            assert!(
+                matches!(
+                    read_subscription(&data[..len]),
+                    Err(DecoderError::CursorError(_))
+                ),
+                "length {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_subscription_is_caught_at_subtree_count() {
+        let data = encode_subscription(1, 0, &[63, 64]);
+
+        // the error reports what was left from the subtree count on,
+        // so nothing after the count was read before finding the keys are cut off
+        let count_offset = 32 + 8 + 4;
+        let len = data.len() - 1;
+        assert!(matches!(
+            read_subscription(&data[..len]),
+            Err(DecoderError::CursorError(CursorError::OversizeError(remaining)))
+                if remaining == len - count_offset
+        ));
+    }
+
+    #[test]
+    fn subscription_covers_first_and_last_timestamp() {
+        // 2 + 1 + 2^4 timestamps
+        let depths = [63, 64, 60];
+        let start_time = 1000;
+        let end_time = start_time + 2 + 1 + 16 - 1;
+
+        let subscription = read_subscription(&encode_subscription(1, start_time, &depths)).unwrap();
+        assert_eq!(subscription.checked_end_time(), Some(end_time));
+
+        let first = subscription.get_subtree(start_time).unwrap();
+        assert_eq!(first.lowest_timestamp, start_time);
+        let last = subscription.get_subtree(end_time).unwrap();
+        assert_eq!(last.highest_timestamp, end_time);
+
+        assert!(subscription.get_subtree(start_time - 1).is_none());
+        assert!(subscription.get_subtree(end_time + 1).is_none());
+    }
+
+    #[test]
+    fn subscription_at_edges_of_timestamp_range() {
+        // starting at 0 and ending at u64::MAX
+        let subscription = read_subscription(&encode_subscription(1, 0, &[1, 1])).unwrap();
+        assert_eq!(subscription.checked_end_time(), Some(u64::MAX));
+        assert_eq!(subscription.get_subtree(0).unwrap().lowest_timestamp, 0);
+        assert_eq!(
+            subscription
+                .get_subtree(u64::MAX)
+                .unwrap()
+                .highest_timestamp,
+            u64::MAX
+        );
+
+        // one past u64::MAX is rejected rather than wrapping around
+        assert!(read_subscription(&encode_subscription(1, 1, &[1, 1])).is_err());
+        assert!(read_subscription(&encode_subscription(1, u64::MAX, &[64])).is_ok());
+        assert!(read_subscription(&encode_subscription(1, u64::MAX, &[64, 64])).is_err());
+    }
+}
@@ -1,7 +1,11 @@
+use core::cell::RefCell;
 use core::fmt::{self, Display, Write};
 
+use bytemuck::{bytes_of, Pod};
+use cortex_m::interrupt::{self, Mutex};
 use thiserror_no_std::Error;
 
+use crate::decoder_context::{DecoderChannelInfoResult, SlotStatus};
 use crate::message::{Message, MessageError, Opcode, MAX_BODY_SIZE};
 
 pub struct Cursor<T> {
@@ -25,18 +29,28 @@ impl<T> Cursor<T>
 where
     T: AsRef<[u8]>,
 {
-    /// Read bytes from this cursor into a buffer.
+    /// Number of bytes after the current offset.
+    pub fn remaining(&self) -> usize {
+        self.buf.as_ref().len() - self.offset
+    }
+
+    /// Get the next `len` bytes without advancing the cursor.
     /// If there is not enough bytes remaining to do so, return an error with how many bytes are left
-    pub fn read_into(&mut self, other: &mut [u8]) -> Result<(), CursorError> {
-        let remainder = &self.buf.as_ref()[self.offset..];
-        if remainder.len() < other.len() {
-            Err(CursorError::OversizeError(remainder.len()))
+    pub(crate) fn peek(&self, len: usize) -> Result<&[u8], CursorError> {
+        if self.remaining() < len {
+            Err(CursorError::OversizeError(self.remaining()))
         } else {
-            other.copy_from_slice(&remainder[..other.len()]);
-            self.offset += other.len();
-            Ok(())
+            Ok(&self.buf.as_ref()[self.offset..][..len])
         }
     }
+
+    /// Read bytes from this cursor into a buffer.
+    /// If there is not enough bytes remaining to do so, return an error with how many bytes are left
+    pub fn read_into(&mut self, other: &mut [u8]) -> Result<(), CursorError> {
+        other.copy_from_slice(self.peek(other.len())?);
+        self.offset += other.len();
+        Ok(())
+    }
 }
 
 impl<T> Cursor<T>
@@ -79,13 +93,68 @@ where
     }
 }
 
+/// Sends `message` to the host tools, after any buffered debug output.
 #[allow(unused)]
 pub fn write_debug_message(message: &str) -> Result<(), MessageError> {
-    let message_bytes = message.as_bytes();
-    for chunk in message_bytes.chunks(MAX_BODY_SIZE) {
-        Message::send_data(Opcode::Debug, chunk)?;
-    }
-    Ok(())
+    // goes through the debug log so nul bytes are replaced and output stays in order
+    write_debug_format(format_args!("{message}"));
+    flush_debug_log()
+}
+
+/// First byte of a debug packet containing a struct rather than text.
+///
+/// Nul bytes in debug text are replaced before it is sent, so text debug packets never contain one,
+/// and host tooling can tell the two apart.
+pub const DEBUG_STRUCT_MARKER: u8 = 0x00;
+
+/// Replaces debug text bytes which could be mistaken for [`DEBUG_STRUCT_MARKER`].
+const DEBUG_MARKER_REPLACEMENT: u8 = b'?';
+
+/// A struct which can be sent to host tooling in a debug packet with [`send_debug_struct`].
+///
+/// Structs sent this way must not contain any secrets.
+///
+/// # Tags
+///
+/// | Tag    | Struct                     |
+/// |--------|----------------------------|
+/// | `0x01` | `DecoderChannelInfoResult` |
+/// | `0x02` | `SlotStatus`               |
+pub trait DebugStruct: Pod {
+    /// Identifies which struct is in a debug packet.
+    const TAG: u8;
+}
+
+impl DebugStruct for DecoderChannelInfoResult {
+    const TAG: u8 = 0x01;
+}
+
+impl DebugStruct for SlotStatus {
+    const TAG: u8 = 0x02;
+}
+
+/// Writes the debug packet body for `value` into `out`, and returns the length of the body.
+///
+/// The body is [`DEBUG_STRUCT_MARKER`], then the tag of the struct,
+/// then the bytes of the struct in memory (little endian, `repr(C)` layout).
+fn encode_debug_struct<T: DebugStruct>(value: &T, out: &mut [u8]) -> Result<usize, CursorError> {
+    let mut cursor = Cursor::new(out);
+    cursor.read_from(&[DEBUG_STRUCT_MARKER, T::TAG])?;
+    cursor.read_from(bytes_of(value))?;
+
+    Ok(cursor.offset)
+}
+
+/// Sends `value` to the host tools as a debug packet, see [`encode_debug_struct`] for the format.
+///
+/// Buffered debug text is sent first, so host tooling sees text and structs in the order they were written.
+pub fn send_debug_struct<T: DebugStruct>(value: &T) -> Result<(), MessageError> {
+    let mut message_buf = [0; MAX_BODY_SIZE];
+    let len =
+        encode_debug_struct(value, &mut message_buf).map_err(|_| MessageError::BodyLengthError)?;
+
+    flush_debug_log()?;
+    Message::send_data(Opcode::Debug, &message_buf[..len])
 }
 
 /// Sends the given `message` bytes as the body of an error packet to the host tools.
@@ -97,29 +166,106 @@ pub fn write_error_bytes(message: &[u8]) -> Result<(), MessageError> {
 }
 
 /// Sends the error message for an error back to the host tools in an error packet.
-pub fn write_error<E: Display>(error: &E) -> Result<(), MessageError> {
+///
+/// The first byte of the packet body is the machine readable error `code`,
+/// followed by the human readable error message.
+pub fn write_error<E: Display>(code: u8, error: &E) -> Result<(), MessageError> {
     let mut message_buf = [0; MAX_BODY_SIZE];
-    let mut writer = Cursor::new(&mut message_buf);
+    message_buf[0] = code;
+
+    let mut writer = Cursor::new(&mut message_buf[1..]);
 
-    if write!(writer, "{error}").is_ok() {
-        let error_len = writer.offset;
-        write_error_bytes(&message_buf[..error_len])
+    let text_len = if write!(writer, "{error}").is_ok() {
+        writer.offset
     } else {
-        write_error_bytes(b"Error occured (error too long to send)")
+        let fallback = b"Error occured (error too long to send)";
+        message_buf[1..][..fallback.len()].copy_from_slice(fallback);
+        fallback.len()
+    };
+
+    write_error_bytes(&message_buf[..1 + text_len])
+}
+
+/// Number of bytes of debug output buffered before it is sent to the host tools.
+const DEBUG_LOG_CAPACITY: usize = 1024;
+
+/// Debug output which has not been sent to the host tools yet.
+struct DebugLog {
+    buf: [u8; DEBUG_LOG_CAPACITY],
+    len: usize,
+}
+
+static DEBUG_LOG: Mutex<RefCell<DebugLog>> = Mutex::new(RefCell::new(DebugLog {
+    buf: [0; DEBUG_LOG_CAPACITY],
+    len: 0,
+}));
+
+impl DebugLog {
+    /// Sends all buffered output as a debug message, and empties the buffer.
+    ///
+    /// Buffered output is dropped even if sending fails.
+    fn flush(&mut self) -> Result<(), MessageError> {
+        let result = if self.len > 0 {
+            Message::send_data(Opcode::Debug, &self.buf[..self.len])
+        } else {
+            Ok(())
+        };
+
+        self.len = 0;
+        result
+    }
+}
+
+impl Write for DebugLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for chunk in s.as_bytes().chunks(DEBUG_LOG_CAPACITY) {
+            if self.len + chunk.len() > DEBUG_LOG_CAPACITY {
+                // a missing host shouldn't stop the decoder, so just lose the output
+                let _ = self.flush();
+            }
+
+            // debug structs are told apart from text by their first byte, so text can't contain it
+            for (out, &byte) in self.buf[self.len..].iter_mut().zip(chunk) {
+                *out = if byte == DEBUG_STRUCT_MARKER {
+                    DEBUG_MARKER_REPLACEMENT
+                } else {
+                    byte
+                };
+            }
+            self.len += chunk.len();
+        }
+
+        Ok(())
     }
 }
 
+/// Sends all buffered debug output to the host tools.
+///
+/// Does nothing if called while debug output is being written, such as when panicking in a print.
+pub fn flush_debug_log() -> Result<(), MessageError> {
+    interrupt::free(|token| match DEBUG_LOG.borrow(token).try_borrow_mut() {
+        Ok(mut log) => log.flush(),
+        Err(_) => Ok(()),
+    })
+}
+
 /// Called internally by print and println macros.
 ///
-/// Prints formatted info as debug messages.
+/// Buffers formatted info, which is sent as debug messages when the buffer fills up
+/// or the host tools request it. Never blocks on or panics because of the host tools.
+#[cfg(not(test))]
 pub fn write_debug_format(args: fmt::Arguments) {
-    let mut message_buf = [0; MAX_BODY_SIZE];
-
-    let mut cursor = Cursor::new(&mut message_buf);
-    cursor.write_fmt(args).unwrap();
-    let message_len = cursor.offset;
+    interrupt::free(|token| {
+        if let Ok(mut log) = DEBUG_LOG.borrow(token).try_borrow_mut() {
+            let _ = log.write_fmt(args);
+        }
+    })
+}
 
-    Message::send_data(Opcode::Debug, &message_buf[..message_len]).unwrap();
+/// Host tests have no uart or interrupts to disable, so debug output goes to stdout instead.
+#[cfg(test)]
+pub fn write_debug_format(args: fmt::Arguments) {
+    std::print!("{args}");
 }
 
 /// Prints to the uart port
@@ -134,3 +280,92 @@ macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::pod_read_unaligned;
+
+    use super::*;
+
+    #[test]
+    fn debug_struct_round_trips() {
+        let status = SlotStatus {
+            slot: 3,
+            channel_id: 0x1234_5678,
+            sequence: 9,
+            write_count: 8999,
+            subscribed: 1,
+        };
+
+        let mut body = [0; MAX_BODY_SIZE];
+        let len = encode_debug_struct(&status, &mut body).unwrap();
+        assert_eq!(len, 2 + size_of::<SlotStatus>());
+        assert_eq!(body[..2], [DEBUG_STRUCT_MARKER, SlotStatus::TAG]);
+
+        // fields are little endian in declaration order, for host tooling to parse
+        assert_eq!(body[2..6], 3u32.to_le_bytes());
+        assert_eq!(body[6..10], 0x1234_5678u32.to_le_bytes());
+
+        let decoded: SlotStatus = pod_read_unaligned(&body[2..len]);
+        assert_eq!(decoded, status);
+    }
+
+    #[test]
+    fn debug_struct_too_big_for_buffer() {
+        let mut body = [0; 2 + size_of::<SlotStatus>() - 1];
+        assert!(encode_debug_struct(&SlotStatus::default(), &mut body).is_err());
+    }
+
+    #[test]
+    fn debug_text_never_contains_struct_marker() {
+        let mut log = DebugLog {
+            buf: [0xff; DEBUG_LOG_CAPACITY],
+            len: 0,
+        };
+        log.write_str("\0a\0").unwrap();
+        log.write_char('\0').unwrap();
+
+        assert_eq!(log.buf[..log.len], *b"?a??");
+    }
+
+    #[test]
+    fn peek_up_to_end() {
+        let mut cursor = Cursor::new([1, 2, 3, 4]);
+        cursor.offset = 1;
+
+        assert_eq!(cursor.peek(0).unwrap(), []);
+        assert_eq!(cursor.peek(3).unwrap(), [2, 3, 4]);
+        // peeking does not advance the cursor
+        assert_eq!(cursor.offset, 1);
+
+        cursor.offset = 4;
+        assert_eq!(cursor.peek(0).unwrap(), []);
+    }
+
+    #[test]
+    fn peek_past_end() {
+        let mut cursor = Cursor::new([1, 2, 3, 4]);
+        cursor.offset = 1;
+        assert!(matches!(cursor.peek(4), Err(CursorError::OversizeError(3))));
+        assert!(matches!(
+            cursor.peek(usize::MAX),
+            Err(CursorError::OversizeError(3))
+        ));
+
+        cursor.offset = 4;
+        assert!(matches!(cursor.peek(1), Err(CursorError::OversizeError(0))));
+    }
+
+    #[test]
+    fn failed_read_does_not_advance() {
+        let mut cursor = Cursor::new([1, 2, 3, 4]);
+        let mut buf = [0; 3];
+        cursor.read_into(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(cursor.offset, 3);
+
+        assert!(cursor.read_into(&mut buf).is_err());
+        assert_eq!(cursor.offset, 3);
+        assert_eq!(cursor.remaining(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Page math for choosing which flash pages the decoder stores data on.
+//!
+//! Only used by the decoder build script, but kept in its own crate so it can be unit tested.
+
+use std::ops::RangeInclusive;
+
+/// Layout of flash, and where the bootloader puts our code in it.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashLayout {
+    /// Address of the first page of flash.
+    pub flash_start: usize,
+    pub page_size: usize,
+    /// Total number of pages in flash.
+    pub page_count: usize,
+    /// Address right after the end of the region the bootloader can put code in.
+    pub code_end: usize,
+}
+
+impl FlashLayout {
+    /// Pages which data can be stored on.
+    ///
+    /// Leaves a gap of one page after the code, and one page before the last (vulnerable / info) page.
+    pub fn usable_pages(&self) -> RangeInclusive<usize> {
+        let first_free_page = (self.code_end - self.flash_start).div_ceil(self.page_size);
+
+        (first_free_page + 1)..=(self.page_count - 2)
+    }
+
+    /// Returns addresses of `count` pages, starting at page number `first_page`
+    /// and placed every `spacing` pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `spacing` is 0, or any of the pages are not in [`FlashLayout::usable_pages`].
+    pub fn spaced_pages(&self, first_page: usize, count: usize, spacing: usize) -> Vec<usize> {
+        assert!(spacing > 0, "data pages must not overlap");
+
+        let usable_pages = self.usable_pages();
+
+        (0..count)
+            .map(|i| first_page + i * spacing)
+            .inspect(|page| {
+                assert!(
+                    usable_pages.contains(page),
+                    "data page {page} is outside of usable pages {usable_pages:?}"
+                )
+            })
+            .map(|page| self.flash_start + page * self.page_size)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Max78000 flash, with code placed where the ectf bootloader puts it.
+    const MAX78000: FlashLayout = FlashLayout {
+        flash_start: 0x10000000,
+        page_size: 0x2000,
+        page_count: 64,
+        code_end: 0x1000e000 + 0x38000,
+    };
+
+    #[test]
+    fn usable_pages_leave_gaps_around_code_and_last_page() {
+        // code ends right at the end of page 34
+        assert_eq!(MAX78000.usable_pages(), 36..=62);
+    }
+
+    #[test]
+    fn usable_pages_round_partial_code_page_up() {
+        let layout = FlashLayout {
+            code_end: MAX78000.code_end + 1,
+            ..MAX78000
+        };
+        assert_eq!(layout.usable_pages(), 37..=62);
+
+        let layout = FlashLayout {
+            code_end: MAX78000.code_end - 1,
+            ..MAX78000
+        };
+        assert_eq!(layout.usable_pages(), 36..=62);
+    }
+
+    #[test]
+    fn spaced_pages_are_usable_and_do_not_overlap() {
+        for (first_page, count, spacing) in [(40, 8, 2), (36, 27, 1), (36, 14, 2), (62, 1, 5)] {
+            let pages = MAX78000.spaced_pages(first_page, count, spacing);
+            assert_eq!(pages.len(), count);
+
+            let usable_pages = MAX78000.usable_pages();
+            for page in &pages {
+                assert_eq!(
+                    page % MAX78000.page_size,
+                    0,
+                    "page {page:#x} is not aligned"
+                );
+                let page_number = (page - MAX78000.flash_start) / MAX78000.page_size;
+                assert!(usable_pages.contains(&page_number));
+            }
+
+            for pair in pages.windows(2) {
+                assert_eq!(pair[1] - pair[0], spacing * MAX78000.page_size);
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_data_pages() {
+        let pages = MAX78000.spaced_pages(40, 8, 2);
+        assert_eq!(pages[0], 0x10050000);
+        assert_eq!(pages[7], 0x1006c000);
+    }
+
+    #[test]
+    fn no_pages() {
+        assert!(MAX78000.spaced_pages(0, 0, 1).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of usable pages")]
+    fn page_after_usable_pages() {
+        // page 62 is the last usable one, this would also use page 64
+        MAX78000.spaced_pages(40, 12, 2);
+        MAX78000.spaced_pages(40, 13, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of usable pages")]
+    fn page_before_usable_pages() {
+        MAX78000.spaced_pages(35, 1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not overlap")]
+    fn zero_spacing() {
+        MAX78000.spaced_pages(40, 2, 0);
+    }
+}
@@ -1,5 +1,10 @@
+#[cfg(feature = "flash-timing")]
+use core::cell::Cell;
 use core::ptr;
 
+#[cfg(feature = "flash-timing")]
+use cortex_m::peripheral::DWT;
+
 use max78000_device::{interrupt, FLC};
 use once_cell::sync::OnceCell;
 
@@ -21,12 +26,41 @@ pub const FLASH_SIZE: usize = 0x80000;
 
 static FLASH: OnceCell<Flash> = OnceCell::new();
 
+/// Kinds of flash operations which the flash controller has to be waited on for.
+#[derive(Debug, Clone, Copy)]
+enum FlashOperation {
+    Erase,
+    Write,
+}
+
+/// How long one kind of flash operation took, in cpu cycles.
+#[cfg(feature = "flash-timing")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlashLatency {
+    /// Cycles taken by the most recent operation.
+    pub last: u32,
+    /// Most cycles taken by any operation since startup.
+    pub max: u32,
+}
+
+/// Latencies of each kind of flash operation.
+#[cfg(feature = "flash-timing")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlashTimings {
+    /// Latency of page erases.
+    pub erase: FlashLatency,
+    /// Latency of 16 byte writes.
+    pub write: FlashLatency,
+}
+
 /// Used to interact with the max78000 flash memory.
 ///
 /// Performs various fuctionality such as writing to and clearing flash.
 #[derive(Debug)]
 pub struct Flash {
     regs: FLC,
+    #[cfg(feature = "flash-timing")]
+    timings: Cell<FlashTimings>,
 }
 
 impl Flash {
@@ -37,7 +71,11 @@ impl Flash {
 
     /// Creates a new Flash instance from the flash controller registers.
     pub(crate) fn new(regs: FLC) -> Self {
-        Flash { regs }
+        Flash {
+            regs,
+            #[cfg(feature = "flash-timing")]
+            timings: Cell::new(FlashTimings::default()),
+        }
     }
 
     pub fn get() -> &'static Self {
@@ -57,6 +95,39 @@ impl Flash {
         }
     }
 
+    /// Waits for a started erase or write to finish.
+    #[cfg(not(feature = "flash-timing"))]
+    fn await_operation(&self, _operation: FlashOperation) {
+        self.await_not_busy();
+    }
+
+    /// Waits for a started erase or write to finish, and records how long it took.
+    ///
+    /// The dwt cycle counter must be enabled.
+    #[cfg(feature = "flash-timing")]
+    fn await_operation(&self, operation: FlashOperation) {
+        let start_cycles = DWT::cycle_count();
+
+        self.await_not_busy();
+
+        let cycles = DWT::cycle_count().wrapping_sub(start_cycles);
+
+        let mut timings = self.timings.get();
+        let latency = match operation {
+            FlashOperation::Erase => &mut timings.erase,
+            FlashOperation::Write => &mut timings.write,
+        };
+        latency.last = cycles;
+        latency.max = latency.max.max(cycles);
+        self.timings.set(timings);
+    }
+
+    /// Gets how long flash erases and writes have taken.
+    #[cfg(feature = "flash-timing")]
+    pub fn timings(&self) -> FlashTimings {
+        self.timings.get()
+    }
+
     /// Starts a flash operation by waiting for all other operations to finish, clearing errors, and unlocking controller.
     fn start_flash_operation(&self) {
         self.await_not_busy();
@@ -145,7 +216,7 @@ impl Flash {
 
         self.regs.ctrl().modify(|_, ctrl| ctrl.pge().start());
 
-        self.await_not_busy();
+        self.await_operation(FlashOperation::Erase);
 
         let result = self.get_and_clear_error();
 
@@ -155,6 +226,40 @@ impl Flash {
         result
     }
 
+    /// Overwrites the page at the given address with zeros, then erases it.
+    ///
+    /// Flash writes can only clear bits, so writing all zero words over the old data clears every bit of it,
+    /// and an incomplete erase afterwards can't leave any of the old data behind.
+    ///
+    /// The flash controller does not require a word to be erased before it is written.
+    /// It only fails writes while it is busy or locked (`INTR.AF`), and flash has no ecc on this chip
+    /// (the gcr ecc registers only cover sysram0), so programming words that already hold data is accepted
+    /// and simply ands the new data into them.
+    ///
+    /// Chunks are overwritten from the end of the page to the start, so if power is lost partway through,
+    /// a validity marker at the end of the page is destroyed before any of the data it marks.
+    ///
+    /// This costs a full page of writes on top of the erase, which wears the page more than [`Flash::erase_page`],
+    /// so it should only be used when wiping secrets, not for regular updates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if address is not flash page aligned.
+    ///
+    /// # Safety
+    ///
+    /// Must not erase any page with executable code, or any page that a refrence currently points to.
+    pub unsafe fn secure_erase_page(&self, address: usize) -> Result<(), HalError> {
+        assert_eq!(address & PAGE_MASK, address, "address not page aligned");
+
+        for (chunk_address, data) in secure_erase_chunks(address) {
+            // safety: chunk is on the page, which the caller guarantees can be erased
+            unsafe { self.write16(chunk_address, &data)? };
+        }
+
+        unsafe { self.erase_page(address) }
+    }
+
     /// Writes 16 bytes of data to a 16 byte aligned address
     ///
     /// # Safety
@@ -178,7 +283,7 @@ impl Flash {
 
         self.regs.ctrl().modify(|_, ctrl| ctrl.wr().start());
 
-        self.await_not_busy();
+        self.await_operation(FlashOperation::Write);
 
         let result = self.get_and_clear_error();
 
@@ -259,6 +364,15 @@ impl Flash {
     }
 }
 
+/// Addresses of the 16 byte chunks of the page at `page_address` and the data written to them,
+/// in the order [`Flash::secure_erase_page`] overwrites them.
+fn secure_erase_chunks(page_address: usize) -> impl Iterator<Item = (usize, [u8; ADDR_ALIGN])> {
+    (page_address..page_address + FLASH_PAGE_SIZE)
+        .step_by(ADDR_ALIGN)
+        .rev()
+        .map(|chunk_address| (chunk_address, [0; ADDR_ALIGN]))
+}
+
 // This is kindof a hack
 // Flash might get messed up if used in an interrupt for example
 // but no interrupts this year
@@ -270,3 +384,40 @@ unsafe impl Sync for Flash {}
 #[allow(non_snake_case)]
 #[interrupt]
 fn FLASH_CONTROLLER() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = FLASH_BASE_ADDR + 40 * FLASH_PAGE_SIZE;
+
+    #[test]
+    fn secure_erase_overwrites_every_chunk_of_page_once() {
+        // zeros clear every bit, whatever was written before
+        assert!(secure_erase_chunks(PAGE).all(|(_, data)| data == [0; ADDR_ALIGN]));
+
+        let mut chunks = secure_erase_chunks(PAGE)
+            .map(|(chunk, _)| chunk)
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), FLASH_PAGE_SIZE / ADDR_ALIGN);
+
+        chunks.sort_unstable();
+        chunks.dedup();
+        assert_eq!(chunks.len(), FLASH_PAGE_SIZE / ADDR_ALIGN);
+
+        assert!(chunks
+            .iter()
+            .all(|&chunk| chunk & ADDR_MASK == chunk && chunk & PAGE_MASK == PAGE));
+    }
+
+    #[test]
+    fn secure_erase_overwrites_end_of_page_first() {
+        let mut chunks = secure_erase_chunks(PAGE);
+
+        assert_eq!(
+            chunks.next(),
+            Some((PAGE + FLASH_PAGE_SIZE - ADDR_ALIGN, [0; ADDR_ALIGN]))
+        );
+        assert_eq!(chunks.last(), Some((PAGE, [0; ADDR_ALIGN])));
+    }
+}
@@ -1,12 +1,19 @@
 use core::cell::RefCell;
 
 use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::DWT;
 use max78000_device::{gcr::clkctrl::SYSCLK_SEL_A, GCR, LPGCR};
 
+use crate::rtc::{Rtc, SUB_SECOND_FREQUENCY};
 use crate::{
     ERTCO_FREQUENCY, EXTCLK_FREQUECNY, IBRO_FREQUENCY, INRO_FREQUENCY, IPO_FREQUENCY, ISO_FREQUENCY,
 };
 
+/// Number of rtc sub second ticks to count system clock cycles over when measuring the system clock.
+///
+/// 256 ticks is 1/16 of a second.
+const SYSCLOCK_MEASURE_TICKS: u32 = 256;
+
 /// Stores gcr used by all peripherals
 static GCR: Mutex<RefCell<Option<Gcr>>> = Mutex::new(RefCell::new(None));
 
@@ -73,6 +80,31 @@ impl Gcr {
         frequency >> clock_divide
     }
 
+    /// Measures the actual frequency of the system clock in ticks per second,
+    /// by counting cpu cycles over a number of rtc ticks.
+    ///
+    /// This can be compared with [`Gcr::get_sysclock_frequency`] to check the assumed clock is correct.
+    /// The dwt cycle counter must be enabled.
+    ///
+    /// Returns `None` if the rtc stops ticking.
+    pub fn measure_sysclock(&self, reference: &Rtc) -> Option<u32> {
+        // give up if the rtc takes over 16 times longer than expected to tick
+        let max_cycles = self.get_sysclock_frequency() / SUB_SECOND_FREQUENCY * 16;
+
+        // start on a tick boundary, so a partial tick is not counted
+        let start_tick = reference.wait_for_tick(max_cycles)?;
+        let start_cycles = DWT::cycle_count();
+
+        let mut current_tick = start_tick;
+        while Rtc::ticks_between(start_tick, current_tick) < SYSCLOCK_MEASURE_TICKS {
+            current_tick = reference.wait_for_tick(max_cycles)?;
+        }
+
+        let cycles = DWT::cycle_count().wrapping_sub(start_cycles);
+
+        Some(cycles * (SUB_SECOND_FREQUENCY / SYSCLOCK_MEASURE_TICKS))
+    }
+
     /// Gets the frequency of the clock used for many peripherals in ticks per second.
     pub fn get_peripheral_clock_frequency(&self) -> u32 {
         self.get_sysclock_frequency() / 2
@@ -104,6 +136,25 @@ impl Gcr {
         while self.regs.clkctrl().read().ibro_rdy().bit_is_clear() {}
     }
 
+    /// Enables the ERTCO (External Real Time Clock Oscillator) clock.
+    ///
+    /// This clock is used by the rtc. Returns false if it is not ready within `timeout_cycles` cpu cycles.
+    /// The dwt cycle counter must be enabled.
+    pub fn enable_ertco_clock(&self, timeout_cycles: u32) -> bool {
+        self.regs
+            .clkctrl()
+            .modify(|_, clckctrl| clckctrl.ertco_en().set_bit());
+
+        let start_cycles = DWT::cycle_count();
+        while self.regs.clkctrl().read().ertco_rdy().bit_is_clear() {
+            if DWT::cycle_count().wrapping_sub(start_cycles) > timeout_cycles {
+                return false;
+            }
+        }
+
+        true
+    }
+
     pub fn set_uart0_clock_enabled(&mut self, enabled: bool) {
         self.regs
             .pclkdis0()
@@ -53,6 +53,45 @@ impl Led {
     }
 }
 
+/// What the device is currently doing, shown as a color on the leds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Peripherals and state are being set up: blue.
+    Booting,
+    /// Idle and waiting for the host tools: green.
+    WaitingForCommand,
+    /// Handling a command from the host tools: cyan (green and blue).
+    Processing,
+    /// Unrecoverable error, such as a panic: red.
+    Error,
+}
+
+impl Status {
+    /// Returns which leds are on for this status, as `[red, green, blue]`.
+    fn leds(self) -> [bool; 3] {
+        match self {
+            Status::Booting => [false, false, true],
+            Status::WaitingForCommand => [false, true, false],
+            Status::Processing => [false, true, true],
+            Status::Error => [true, false, false],
+        }
+    }
+}
+
+/// Shows `status` on the leds, and turns off all leds not used by it.
+pub fn set_status(status: Status) {
+    for (led, on) in [Led::Red, Led::Green, Led::Blue]
+        .into_iter()
+        .zip(status.leds())
+    {
+        if on {
+            led_on(led);
+        } else {
+            led_off(led);
+        }
+    }
+}
+
 /// Turns on the given led.
 pub fn led_on(led: Led) {
     let config = LED_GPIO_PINS[led as usize];
@@ -85,4 +124,6 @@ pub(crate) fn init() {
             gpio.output_set(config.gpio_type, config.pin_mask);
         }
     });
+
+    set_status(Status::Booting);
 }
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub mod flash;
 pub mod gcr;
@@ -6,8 +6,13 @@ pub mod gpio;
 pub mod icc;
 pub mod led;
 pub mod mpu;
+pub mod power;
 pub mod prelude;
+pub mod rtc;
+pub mod spsc;
+pub mod trng;
 pub mod uart;
+pub mod wdt;
 
 use thiserror_no_std::Error;
 
@@ -16,7 +21,10 @@ pub use gcr::Gcr;
 pub use gpio::Gpio;
 pub use icc::Icc;
 pub use mpu::Mpu;
+pub use rtc::{Rtc, StoppedRtc};
+pub use trng::Trng;
 pub use uart::Uart;
+pub use wdt::Wdt;
 
 // frequency of various clocks on the board
 const ISO_FREQUENCY: u32 = 60000000;
@@ -36,28 +44,42 @@ pub enum HalError {
     I2cConnectionError,
     #[error("Error: timeout occured")]
     Timeout,
+    #[error("Error: trng failed health check")]
+    TrngError,
 }
 
 /// Contains various peripheralls of the max78000 device.
 pub struct Peripherals {
     pub icc: Icc,
     pub mpu: Mpu,
+    pub trng: Trng,
+    pub rtc: StoppedRtc,
+    pub wdt: Wdt,
 }
 
 impl Peripherals {
     /// Initializes all peripherals and returns them.
     pub fn take() -> Option<Peripherals> {
-        let cortex_m::peripheral::Peripherals { MPU, .. } =
-            cortex_m::peripheral::Peripherals::take()?;
+        let cortex_m::peripheral::Peripherals {
+            MPU,
+            mut SCB,
+            mut DCB,
+            mut DWT,
+            ..
+        } = cortex_m::peripheral::Peripherals::take()?;
 
         let max78000_device::Peripherals {
             FLC,
             GCR,
             ICC0,
             LPGCR,
+            MCR,
             GPIO0,
             GPIO2,
             UART,
+            TRNG,
+            RTC,
+            WDT,
             ..
         } = max78000_device::Peripherals::take()?;
 
@@ -65,16 +87,31 @@ impl Peripherals {
         Gpio::init(GPIO0, GPIO2);
         Uart::init(UART);
         Flash::init(FLC);
+        power::init(&mut SCB);
+
+        // cycle counter is used for timing against the rtc
+        DCB.enable_trace();
+        DWT.enable_cycle_counter();
 
         led::init();
 
         Some(Peripherals {
             icc: Icc::new(ICC0),
             mpu: Mpu::new(MPU),
+            trng: Trng::new(TRNG),
+            rtc: StoppedRtc::new(RTC, MCR),
+            wdt: Wdt::new(WDT),
         })
     }
 }
 
+/// Resets the whole device, the same as pressing the reset button.
+///
+/// Firmware starts over from the beginning, so all peripherals are initialized again by [`Peripherals::take`].
+pub fn reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
 /// Aligns `addr` up to the power 2 alignment `align`
 /// `align` must be a power of 2
 pub const fn align_up(addr: usize, align: usize) -> usize {
@@ -0,0 +1,41 @@
+use cortex_m::asm;
+use cortex_m::interrupt::InterruptNumber;
+use cortex_m::peripheral::{NVIC, SCB};
+
+/// Bit in the system control register which makes any pending interrupt wake the core from `wfe`,
+/// even if it is disabled in the nvic.
+const SCR_SEVONPEND: u32 = 1 << 4;
+
+/// Sets up the core so [`sleep_until`] can be woken by peripheral interrupt flags.
+pub(crate) fn init(scb: &mut SCB) {
+    // safety: only sevonpend is set, which just adds more wakeup events
+    unsafe { scb.scr.modify(|scr| scr | SCR_SEVONPEND) };
+
+    // deep sleep would stop peripheral clocks, so the uart could not receive anything
+    scb.clear_sleepdeep();
+}
+
+/// Puts the core to sleep until `ready` returns true.
+///
+/// The core wakes up whenever `wake_source` becomes pending and checks `ready` again.
+/// No interrupts are enabled in the nvic, so no handlers run,
+/// a peripheral just needs its interrupt enabled to be able to wake the core.
+/// Other interrupts can also wake the core, which just checks `ready` again.
+///
+/// `ready` should clear the interrupt flag of the peripheral it is waiting on,
+/// otherwise the interrupt stays pending and the core will not stay asleep.
+pub fn sleep_until(wake_source: impl InterruptNumber, mut ready: impl FnMut() -> bool) {
+    loop {
+        // an interrupt only generates a wakeup event when it becomes pending,
+        // so the wake source has to be cleared to be able to wake the core again.
+        // other interrupts are left pending for whatever handles them
+        NVIC::unpend(wake_source);
+
+        if ready() {
+            return;
+        }
+
+        // if an interrupt became pending after the check, this returns immediately
+        asm::wfe();
+    }
+}
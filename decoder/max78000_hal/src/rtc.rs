@@ -0,0 +1,101 @@
+use cortex_m::peripheral::DWT;
+use max78000_device::{MCR, RTC};
+
+use crate::{Gcr, HalError};
+
+/// Frequency of the rtc sub second counter in ticks per second.
+pub const SUB_SECOND_FREQUENCY: u32 = 4096;
+
+/// Mask of the bits used by the 12 bit sub second counter.
+const SUB_SECOND_MASK: u16 = 0xfff;
+
+/// How long to wait for the external rtc oscillator to start before giving up, in milliseconds.
+///
+/// 32.768 KHz crystals usually start within a few hundred milliseconds, but if there is no crystal it never will.
+const ERTCO_START_TIMEOUT_MS: u32 = 1000;
+
+/// Real time clock which has not been started yet.
+///
+/// Starting the rtc can take up to [`ERTCO_START_TIMEOUT_MS`], so it is left to whoever needs it.
+pub struct StoppedRtc {
+    regs: RTC,
+    mcr: MCR,
+}
+
+impl StoppedRtc {
+    pub(crate) fn new(regs: RTC, mcr: MCR) -> Self {
+        StoppedRtc { regs, mcr }
+    }
+
+    /// Starts the ertco and the rtc.
+    ///
+    /// Returns [`HalError::Timeout`] if the ertco did not start within [`ERTCO_START_TIMEOUT_MS`],
+    /// such as if there is no crystal. The dwt cycle counter must be enabled.
+    pub fn start(self) -> Result<Rtc, HalError> {
+        self.mcr.ctrl().modify(|_, ctrl| ctrl.ertco_en().set_bit());
+
+        let ertco_ready = Gcr::with(|gcr| {
+            let timeout_cycles = gcr.get_sysclock_frequency() / 1000 * ERTCO_START_TIMEOUT_MS;
+            gcr.enable_ertco_clock(timeout_cycles)
+        });
+        if !ertco_ready {
+            return Err(HalError::Timeout);
+        }
+
+        let rtc = Rtc { regs: self.regs };
+
+        // rtc control register can only be written while writes are enabled and the rtc is not busy
+        rtc.await_not_busy();
+        rtc.regs.ctrl().modify(|_, ctrl| ctrl.wr_en().set_bit());
+        rtc.await_not_busy();
+        rtc.regs.ctrl().modify(|_, ctrl| ctrl.en().set_bit());
+        rtc.await_not_busy();
+        rtc.regs.ctrl().modify(|_, ctrl| ctrl.wr_en().clear_bit());
+
+        Ok(rtc)
+    }
+}
+
+/// Real time clock, driven by the external 32.768 KHz oscillator (ertco).
+///
+/// Mostly useful as a clock source independent from the system clock.
+pub struct Rtc {
+    regs: RTC,
+}
+
+impl Rtc {
+    /// Busy waits until the rtc has finished synchronizing register writes.
+    fn await_not_busy(&self) {
+        while self.regs.ctrl().read().busy().bit_is_set() {}
+    }
+
+    /// Gets the current value of the 12 bit sub second counter.
+    pub fn sub_seconds(&self) -> u16 {
+        self.regs.ssec().read().ssec().bits()
+    }
+
+    /// Busy waits until the sub second counter changes, and returns the new value.
+    ///
+    /// Returns `None` if the counter did not change within `max_cycles` cpu cycles.
+    /// The dwt cycle counter must be enabled.
+    pub fn wait_for_tick(&self, max_cycles: u32) -> Option<u16> {
+        let start_cycles = DWT::cycle_count();
+        let start = self.sub_seconds();
+
+        loop {
+            let now = self.sub_seconds();
+            if now != start {
+                return Some(now);
+            }
+
+            if DWT::cycle_count().wrapping_sub(start_cycles) > max_cycles {
+                return None;
+            }
+        }
+    }
+
+    /// Number of sub second ticks between `start` and `end`, accounting for the counter wrapping.
+    pub fn ticks_between(start: u16, end: u16) -> u32 {
+        u32::from(end.wrapping_sub(start) & SUB_SECOND_MASK)
+    }
+}
@@ -0,0 +1,285 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fixed capacity single producer single consumer queue.
+///
+/// Meant for passing data from an interrupt handler to the main loop (or the other way around)
+/// without a critical section. The queue is split into a [`Producer`] and a [`Consumer`],
+/// each of which can be moved to a different context.
+pub struct Queue<T, const N: usize> {
+    /// Number of items ever popped, only written by the consumer.
+    head: AtomicUsize,
+    /// Number of items ever pushed, only written by the producer.
+    tail: AtomicUsize,
+    buffer: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+
+// safety: producer and consumer never access the same slot at the same time,
+// and slot ownership is handed over with release / acquire on head and tail
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        Queue {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+        }
+    }
+
+    /// Splits the queue into a producer and consumer half.
+    ///
+    /// Taking `&mut self` ensures there is only ever 1 producer and 1 consumer.
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    /// Maximum number of items the queue can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of items currently in the queue.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Returns pointer to the slot which the `index`th item ever pushed is stored in.
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        // safety: index % N is always within the buffer
+        unsafe { self.buffer.get().cast::<MaybeUninit<T>>().add(index % N) }
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+
+        let mut index = head;
+        while index != tail {
+            // safety: every slot between head and tail was written by the producer and not yet read
+            unsafe { (*self.slot(index)).assume_init_drop() };
+            index = index.wrapping_add(1);
+        }
+    }
+}
+
+/// Pushing half of a [`Queue`].
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+}
+
+// safety: only 1 producer exists, so moving it to another context is fine if items can be sent
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Adds `item` to the back of the queue.
+    ///
+    /// Returns `item` back if the queue is full.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        // only the producer writes tail
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let head = self.queue.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) == N {
+            return Err(item);
+        }
+
+        // safety: slot is not between head and tail, so the consumer will not read it
+        unsafe { (*self.queue.slot(tail)).write(item) };
+
+        // publish the slot to the consumer
+        self.queue
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.is_full()
+    }
+}
+
+/// Popping half of a [`Queue`].
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+}
+
+// safety: only 1 consumer exists, so moving it to another context is fine if items can be sent
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Removes the item at the front of the queue, or returns `None` if the queue is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        // only the consumer writes head
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let tail = self.queue.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        // safety: slot is between head and tail, so it has been written and the producer will not touch it
+        let item = unsafe { (*self.queue.slot(head)).assume_init_read() };
+
+        // give the slot back to the producer
+        self.queue
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Some(item)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn empty_queue_pops_nothing() {
+        let mut queue = Queue::<u32, 4>::new();
+        let (_, mut consumer) = queue.split();
+
+        assert!(consumer.is_empty());
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn full_queue_rejects_push() {
+        let mut queue = Queue::<u32, 4>::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        for i in 0..4 {
+            assert!(!producer.is_full());
+            producer.push(i).unwrap();
+        }
+        assert!(producer.is_full());
+        assert_eq!(producer.push(4), Err(4));
+
+        // popping one makes room for exactly one more
+        assert_eq!(consumer.pop(), Some(0));
+        producer.push(4).unwrap();
+        assert_eq!(producer.push(5), Err(5));
+
+        for i in 1..5 {
+            assert_eq!(consumer.pop(), Some(i));
+        }
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn items_wrap_around_buffer_in_order() {
+        let mut queue = Queue::<u32, 3>::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        // keep 2 items queued, so every push and pop eventually crosses the end of the buffer
+        let mut next_push = 0;
+        let mut next_pop = 0;
+        for _ in 0..2 {
+            producer.push(next_push).unwrap();
+            next_push += 1;
+        }
+        for _ in 0..10 {
+            producer.push(next_push).unwrap();
+            next_push += 1;
+            assert_eq!(consumer.pop(), Some(next_pop));
+            next_pop += 1;
+        }
+
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn counters_wrap_around_usize() {
+        let mut queue = Queue::<u32, 4>::new();
+        *queue.head.get_mut() = usize::MAX - 1;
+        *queue.tail.get_mut() = usize::MAX - 1;
+
+        let (mut producer, mut consumer) = queue.split();
+        for i in 0..4 {
+            producer.push(i).unwrap();
+        }
+        assert_eq!(producer.push(4), Err(4));
+
+        for i in 0..4 {
+            assert_eq!(consumer.pop(), Some(i));
+        }
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn dropping_queue_drops_queued_items() {
+        let item = Rc::new(());
+        {
+            let mut queue = Queue::<Rc<()>, 4>::new();
+            let (mut producer, mut consumer) = queue.split();
+            for _ in 0..3 {
+                producer.push(item.clone()).unwrap();
+            }
+            drop(consumer.pop());
+            assert_eq!(Rc::strong_count(&item), 3);
+        }
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn two_thread_stress() {
+        const ITEMS: u64 = 1_000_000;
+
+        let mut queue = Queue::<u64, 8>::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for i in 0..ITEMS {
+                    let mut item = i;
+                    while let Err(rejected) = producer.push(item) {
+                        item = rejected;
+                        thread::yield_now();
+                    }
+                }
+            });
+
+            // every item arrives exactly once and in order
+            let mut expected = 0;
+            while expected < ITEMS {
+                match consumer.pop() {
+                    Some(item) => {
+                        assert_eq!(item, expected);
+                        expected += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+            assert_eq!(consumer.pop(), None);
+        });
+    }
+}
@@ -0,0 +1,206 @@
+use max78000_device::TRNG;
+use rand_core::{impls, CryptoRng, RngCore};
+
+use crate::{Gcr, HalError};
+
+/// Number of bytes sampled by the startup health check.
+const SELF_TEST_SAMPLE_SIZE: usize = 512;
+
+/// Min entropy per byte assumed by the health check cutoffs.
+///
+/// This is very conservative for the hardware trng, so a healthy trng will basically never fail,
+/// while one that is stuck or heavily biased will fail immediately.
+const ASSUMED_ENTROPY_BITS_PER_BYTE: usize = 4;
+
+/// Repetition count cutoff: `1 + ceil(20 / H)`, giving a false positive rate of 2^-20.
+const REPETITION_COUNT_CUTOFF: usize = 1 + 20_usize.div_ceil(ASSUMED_ENTROPY_BITS_PER_BYTE);
+
+/// Adaptive proportion cutoff for a 512 sample window with H = 4 and a false positive rate of 2^-20.
+///
+/// This is the critical value of the binomial distribution B(512, 2^-4), computed ahead of time.
+const ADAPTIVE_PROPORTION_CUTOFF: usize = 62;
+
+/// Chi-square cutoff for a byte histogram (255 degrees of freedom) at a significance of 0.001.
+///
+/// A uniform source exceeds this 0.1% of the time, a biased one much more often.
+const CHI_SQUARE_CUTOFF: u64 = 330;
+
+/// Most bytes [`Trng::measure_entropy`] can sample, so the chi-square sum does not overflow.
+pub const MAX_ENTROPY_SAMPLES: u32 = 1 << 20;
+
+/// Statistics about a sample of trng output, from [`Trng::measure_entropy`].
+///
+/// Only statistics are kept, none of the sampled bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct EntropyReport {
+    /// Number of bytes sampled.
+    pub sample_count: u32,
+    /// Number of times each byte value was sampled.
+    pub histogram: [u32; 256],
+    /// Chi-square statistic of the histogram against a uniform distribution, rounded down.
+    pub chi_square: u64,
+    /// Estimated min entropy per byte in 16.16 fixed point bits, from the most common byte value.
+    pub min_entropy: u32,
+}
+
+impl EntropyReport {
+    /// Checks if the histogram is close enough to uniform.
+    ///
+    /// Only meaningful with enough samples for every byte value to be expected at least 5 times (1280 bytes).
+    pub fn passes_chi_square(&self) -> bool {
+        self.chi_square <= CHI_SQUARE_CUTOFF
+    }
+}
+
+/// Computes log2 of `x` in 16.16 fixed point.
+///
+/// `x` must not be 0.
+fn log2_fixed(x: u64) -> u32 {
+    let leading_zeros = x.leading_zeros();
+    let mut log2 = (63 - leading_zeros) << 16;
+
+    // mantissa in [1, 2) as 1.31 fixed point
+    let mut mantissa = (x << leading_zeros) >> 32;
+    for bit in (0..16).rev() {
+        // squaring doubles the log, so the integer part of the new log is the next fractional bit
+        mantissa = (mantissa * mantissa) >> 31;
+        if mantissa >= 1 << 32 {
+            mantissa >>= 1;
+            log2 |= 1 << bit;
+        }
+    }
+
+    log2
+}
+
+/// True random number generator.
+pub struct Trng {
+    regs: TRNG,
+}
+
+impl Trng {
+    pub(crate) fn new(regs: TRNG) -> Self {
+        Gcr::with(|gcr| gcr.set_trng_clock_enabled(true));
+
+        Trng { regs }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.regs.status().read().rdy().is_ready()
+    }
+
+    /// Waits for the trng to have a new random number and returns it.
+    pub fn gen_u32(&mut self) -> u32 {
+        while !self.is_ready() {}
+
+        self.regs.data().read().data().bits()
+    }
+
+    /// Generates a 32 byte nonce, suitable for seeding a csprng.
+    pub fn gen_nonce(&mut self) -> [u8; 32] {
+        let mut nonce = [0; 32];
+        self.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    /// Runs a basic health check over a sample of trng output.
+    ///
+    /// This performs the repetition count and adaptive proportion tests from NIST SP 800-90B
+    /// on each byte of output. It is meant to catch a dead (stuck or heavily biased) trng,
+    /// not to prove the output is good random data.
+    ///
+    /// Callers should refuse to continue if this fails, since all nonces would be predictable.
+    pub fn self_test(&mut self) -> Result<(), HalError> {
+        let mut samples = [0; SELF_TEST_SAMPLE_SIZE];
+        self.fill_bytes(&mut samples);
+
+        // repetition count test: no byte should repeat many times in a row
+        let mut repetition_count = 1;
+        for window in samples.windows(2) {
+            if window[0] == window[1] {
+                repetition_count += 1;
+                if repetition_count >= REPETITION_COUNT_CUTOFF {
+                    return Err(HalError::TrngError);
+                }
+            } else {
+                repetition_count = 1;
+            }
+        }
+
+        // adaptive proportion test: first byte of window should not make up too much of the window
+        let first_sample_count = samples.iter().filter(|&&byte| byte == samples[0]).count();
+        if first_sample_count >= ADAPTIVE_PROPORTION_CUTOFF {
+            return Err(HalError::TrngError);
+        }
+
+        Ok(())
+    }
+
+    /// Samples `sample_count` bytes of trng output and computes statistics about them.
+    ///
+    /// This is for diagnosing a suspected bad trng, it is slow and should not be used on startup.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sample_count` is 0 or more than [`MAX_ENTROPY_SAMPLES`].
+    pub fn measure_entropy(&mut self, sample_count: u32) -> EntropyReport {
+        assert!(
+            (1..=MAX_ENTROPY_SAMPLES).contains(&sample_count),
+            "invalid number of samples"
+        );
+
+        let mut histogram = [0u32; 256];
+        let mut samples = [0; 64];
+        let mut remaining = sample_count as usize;
+        while remaining > 0 {
+            let samples = &mut samples[..remaining.min(64)];
+            self.fill_bytes(samples);
+
+            for &byte in samples.iter() {
+                histogram[usize::from(byte)] += 1;
+            }
+
+            remaining -= samples.len();
+        }
+
+        // chi square is sum((count - n / 256)^2 / (n / 256)), scaled to stay in integers
+        let n = u64::from(sample_count);
+        let chi_square = histogram
+            .iter()
+            .map(|&count| (256 * u64::from(count)).abs_diff(n).pow(2))
+            .sum::<u64>()
+            / (256 * n);
+
+        // min entropy is -log2(p_max) = log2(n) - log2(max count)
+        let max_count = histogram.iter().copied().max().unwrap_or(1);
+        let min_entropy = log2_fixed(n) - log2_fixed(u64::from(max_count));
+
+        EntropyReport {
+            sample_count,
+            histogram,
+            chi_square,
+            min_entropy,
+        }
+    }
+}
+
+impl RngCore for Trng {
+    fn next_u32(&mut self) -> u32 {
+        self.gen_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Trng {}
@@ -6,7 +6,8 @@ use crate::gcr::Gcr;
 use crate::gpio::{
     ConfigureIoOptions, Gpio, GpioPadConfig, GpioPinFunction, GpioPinVoltage, GpioType,
 };
-use crate::IBRO_FREQUENCY;
+use crate::{power, IBRO_FREQUENCY};
+use cortex_m::interrupt::InterruptNumber;
 use max78000_device::UART;
 use once_cell::sync::OnceCell;
 
@@ -14,6 +15,19 @@ const MAX_CLOCK_DIVISOR: u32 = (1 << 20) - 1;
 
 static UART: OnceCell<Uart> = OnceCell::new();
 
+/// Interrupt of uart 0, used to wake from sleep.
+///
+/// The device crate interrupt list is missing the uart interrupts, so this is its own type.
+#[derive(Debug, Clone, Copy)]
+struct Uart0Interrupt;
+
+// safety: irq 14 is uart 0 in the max78000 interrupt vector table
+unsafe impl InterruptNumber for Uart0Interrupt {
+    fn number(self) -> u16 {
+        14
+    }
+}
+
 /// Gets a reference to the global uart.
 pub fn uart() -> &'static Uart {
     UART.get().expect("uart not yet initialized")
@@ -75,6 +89,11 @@ impl Uart {
             unsafe { ctrl.rx_thd_val().bits(1) }
         });
 
+        // interrupt is never enabled in the nvic, this is only used to wake from sleep
+        self.regs
+            .int_en()
+            .modify(|_, int_en| int_en.rx_thd().set_bit());
+
         self.set_frequency(115200);
     }
 
@@ -131,6 +150,16 @@ impl Uart {
         self.regs.fifo().read().data().bits()
     }
 
+    /// Sleeps until there is data to read, rather than busy waiting.
+    pub fn sleep_until_readable(&self) {
+        power::sleep_until(Uart0Interrupt, || {
+            // clear flag so uart interrupt is only pending again once more data is received
+            self.regs.int_fl().write(|int_fl| int_fl.rx_thd().set_bit());
+
+            !self.is_receive_empty()
+        });
+    }
+
     /// Reads in bytes to a buffer
     pub fn read_bytes(&self, buffer: &mut [u8]) {
         for byte in buffer.iter_mut() {
@@ -0,0 +1,51 @@
+use max78000_device::WDT;
+
+/// Cause of the last reset, as far as the max78000 records it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// Watchdog timer was not reset before its window ended.
+    WatchdogLate,
+    /// Watchdog timer was reset before its window started.
+    WatchdogEarly,
+    /// Power on, reset pin, software reset, brownout, or lockup.
+    ///
+    /// Only the watchdog records why it reset the device. The gcr `RST0` and `RST1` registers
+    /// are self clearing reset requests, and `SYSST` only holds the ICE lock flag,
+    /// so these can't be told apart.
+    Other,
+}
+
+/// Watchdog timer.
+///
+/// The watchdog is never enabled, this is only used to read why it last reset the device.
+pub struct Wdt {
+    regs: WDT,
+}
+
+impl Wdt {
+    pub(crate) fn new(regs: WDT) -> Self {
+        Wdt { regs }
+    }
+
+    /// Gets the cause of the last reset, and clears it.
+    ///
+    /// The only causes recorded are the watchdog reset flags, which stay set across resets
+    /// until cleared, so this should be called once early on startup.
+    pub fn reset_cause(&mut self) -> ResetCause {
+        let ctrl = self.regs.ctrl().read();
+
+        let cause = if ctrl.rst_late().bit_is_set() {
+            ResetCause::WatchdogLate
+        } else if ctrl.rst_early().bit_is_set() {
+            ResetCause::WatchdogEarly
+        } else {
+            ResetCause::Other
+        };
+
+        self.regs
+            .ctrl()
+            .modify(|_, ctrl| ctrl.rst_late().clear_bit().rst_early().clear_bit());
+
+        cause
+    }
+}
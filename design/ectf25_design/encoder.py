@@ -57,7 +57,7 @@ class Encoder:
 
         channel_keys = self.secrets.channels[channel]
 
-        if channel == 0:
+        if channel in self.secrets.emergency_channels:
             key = channel_keys.root_key
         else:
             key = derive_node(channel_keys.root_key, timestamp).key
@@ -12,6 +12,7 @@ Copyright: Copyright (c) 2025 The MITRE Corporation
 
 import argparse
 import json
+import os
 from pathlib import Path
 
 from loguru import logger
@@ -29,10 +30,17 @@ def gen_secrets(channels: list[int]) -> bytes:
         Channel 0 is the emergency broadcast, which will always be valid and will
         NOT be included in this list
 
+    Extra emergency channels can be given as a comma separated list in the
+    EMERGENCY_CHANNELS environment variable. Like channel 0, these are always valid.
+
     :returns: Contents of the secrets file
     """
 
-    return GlobalSecrets.generate(channels).to_json().encode()
+    emergency_channels = [
+        int(channel) for channel in os.environ.get("EMERGENCY_CHANNELS", "").split(",") if channel
+    ]
+
+    return GlobalSecrets.generate(channels, emergency_channels).to_json().encode()
 
 
 def parse_args():
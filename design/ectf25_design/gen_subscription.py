@@ -39,9 +39,9 @@ def gen_subscription(
     :param channel: Channel to enable
     """
 
-    assert channel != 0, "Cannot generate subscription for emergency channel"
-
     secrets: GlobalSecrets = GlobalSecrets.from_json(secrets.decode("ascii"))
+
+    assert channel not in secrets.emergency_channels, "Cannot generate subscription for emergency channel"
     channel_keys = secrets.channels[channel]
 
     assert start <= end
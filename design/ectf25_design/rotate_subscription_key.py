@@ -0,0 +1,107 @@
+"""
+Rotates the subscription signing key in a secrets file.
+
+Deployed decoders only accept the signing keys they were built with, so the new key
+can't be used until every decoder has been rebuilt. A rotation is rolled out in steps:
+
+1. Rotate with `--staged`. Decoders built from the new secrets file accept both keys,
+   but subscriptions keep being signed with the old key, which deployed decoders accept.
+2. Rebuild and reflash every decoder with the new secrets file.
+3. Run with `--finish` to sign subscriptions with the new key.
+
+Rotating without `--staged` signs with the new key straight away, so subscriptions only
+work on decoders rebuilt after the rotation.
+"""
+
+import argparse
+from pathlib import Path
+
+from loguru import logger
+
+from .util import GlobalSecrets
+
+
+def rotate_subscription_key(secrets: bytes, keep_previous: int, staged: bool = False) -> bytes:
+    """Generate the contents of a secrets file with a new subscription signing key
+
+    :param secrets: Contents of the secrets file generated by ectf25_design.gen_secrets
+    :param keep_previous: Number of old signing keys decoders should still accept
+    :param staged: Keep signing subscriptions with the old key until the rollout is finished
+
+    :returns: Contents of the new secrets file
+    """
+
+    secrets: GlobalSecrets = GlobalSecrets.from_json(secrets.decode("ascii"))
+    was_signing_with_previous = secrets.signing_previous_key_index is not None
+    secrets.rotate_subscription_signing_key(keep_previous)
+
+    # the key which was current is now the first previous key
+    if staged and not was_signing_with_previous:
+        secrets.sign_with_previous_subscription_key(0)
+
+    return secrets.to_json().encode()
+
+
+def finish_subscription_key_rollout(secrets: bytes) -> bytes:
+    """Generate the contents of a secrets file which signs subscriptions with the current key
+
+    Only do this once every decoder has been rebuilt with the current key.
+
+    :param secrets: Contents of the secrets file generated by ectf25_design.gen_secrets
+
+    :returns: Contents of the new secrets file
+    """
+
+    secrets: GlobalSecrets = GlobalSecrets.from_json(secrets.decode("ascii"))
+    secrets.sign_with_previous_subscription_key(None)
+
+    return secrets.to_json().encode()
+
+
+def parse_args():
+    parser = argparse.ArgumentParser()
+    parser.add_argument(
+        "secrets_file",
+        type=Path,
+        help="Path to the secrets file to rotate the subscription key of, which is overwritten",
+    )
+    parser.add_argument(
+        "--keep-previous",
+        type=int,
+        default=1,
+        help="Number of old signing keys which are still accepted",
+    )
+    mode = parser.add_mutually_exclusive_group()
+    mode.add_argument(
+        "--staged",
+        action="store_true",
+        help="Keep signing subscriptions with the old key until --finish",
+    )
+    mode.add_argument(
+        "--finish",
+        action="store_true",
+        help="Don't rotate, start signing subscriptions with the current key",
+    )
+    return parser.parse_args()
+
+
+def main():
+    args = parse_args()
+
+    if args.finish:
+        secrets = finish_subscription_key_rollout(args.secrets_file.read_bytes())
+        args.secrets_file.write_bytes(secrets)
+
+        logger.success(f"Signing subscriptions with current key in {str(args.secrets_file.absolute())}")
+        return
+
+    secrets = rotate_subscription_key(
+        args.secrets_file.read_bytes(), args.keep_previous, args.staged
+    )
+    args.secrets_file.write_bytes(secrets)
+
+    logger.success(f"Rotated subscription key in {str(args.secrets_file.absolute())}")
+
+
+if __name__ == "__main__":
+    main()
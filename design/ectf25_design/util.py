@@ -92,18 +92,71 @@ class GlobalSecrets:
     # Ed25519 private key used to sign subscription payloads.
     subscribe_private_key: bytes
 
+    # Subscription signing keys from before the last key rotations, most recent first.
+    # Decoders built with these secrets still accept subscriptions signed with them.
+    previous_subscribe_private_keys: list[bytes]
+
+    # Index into previous_subscribe_private_keys of the key subscriptions are signed with,
+    # or None to sign with subscribe_private_key.
+    # Set while decoders built before a rotation are still deployed, since they don't accept the new key.
+    signing_previous_key_index: int | None
+
     channels: dict[int, ChannelKey]
 
+    # Channels which are always decodable without a subscription.
+    # The root key of these channels is used directly as the encryption key.
+    # Always contains channel 0.
+    emergency_channels: list[int]
+
     def subscription_signing_key_for_decoder(self, decoder_id: int) -> eddsa.EdDSASigScheme:
         # decoder id must be 4 byte unsigned integer
         verify_decoder(decoder_id)
         decoder_id_bytes = struct.pack("<I", decoder_id)
 
         # make signing keypair unique per decoder
-        private_key = derive_key(self.subscribe_private_key, decoder_id_bytes)
+        private_key = derive_key(self.active_subscribe_private_key(), decoder_id_bytes)
 
         return bytes_to_eddsa_key(private_key)
 
+    def active_subscribe_private_key(self) -> bytes:
+        """Gets the subscription signing key new subscriptions are signed with."""
+
+        if self.signing_previous_key_index is None:
+            return self.subscribe_private_key
+
+        return self.previous_subscribe_private_keys[self.signing_previous_key_index]
+
+    def sign_with_previous_subscription_key(self, index: int | None):
+        """Signs new subscriptions with `previous_subscribe_private_keys[index]`,
+        or with the current key if `index` is None."""
+
+        assert index is None or 0 <= index < len(self.previous_subscribe_private_keys)
+
+        self.signing_previous_key_index = index
+
+    def rotate_subscription_signing_key(self, keep_previous: int = 1):
+        """Replaces the subscription signing key with a new one.
+
+        There is one subscription signing key for the whole deployment, so this rotates
+        it for subscriptions to every channel. Channel keys are not changed.
+
+        Up to `keep_previous` old signing keys are kept, so decoders built after the
+        rotation still accept subscriptions signed before it. Older keys are revoked.
+        If subscriptions are being signed with a previous key, it keeps being used,
+        so it can't be revoked.
+        """
+
+        assert keep_previous >= 0
+
+        if self.signing_previous_key_index is not None:
+            # the key moves back one place in the list
+            assert self.signing_previous_key_index + 1 < keep_previous, "Cannot revoke the signing key"
+            self.signing_previous_key_index += 1
+
+        previous_keys = [self.subscribe_private_key] + self.previous_subscribe_private_keys
+        self.previous_subscribe_private_keys = previous_keys[:keep_previous]
+        self.subscribe_private_key = random(32)
+
     def subscription_key_for_decoder(self, decoder_id: int) -> bytes:
         # decoder id must be 4 byte unsigned integer
         verify_decoder(decoder_id)
@@ -113,24 +166,34 @@ class GlobalSecrets:
         return derive_key(self.subscribe_root_key, decoder_id_bytes)
 
     @classmethod
-    def generate(cls, channel_ids: list[int]) -> Self:
+    def generate(cls, channel_ids: list[int], emergency_channel_ids: list[int] | None = None) -> Self:
+        if emergency_channel_ids is None:
+            emergency_channel_ids = []
+
         channels = {}
         # channel 0 always exists
         channels[0] = ChannelKey.generate()
 
-        for channel_id in channel_ids:
+        for channel_id in channel_ids + emergency_channel_ids:
             channels[channel_id] = ChannelKey.generate()
 
         return cls(
             subscribe_root_key=random(32),
             subscribe_private_key=random(32),
+            previous_subscribe_private_keys=[],
+            signing_previous_key_index=None,
             channels=channels,
+            emergency_channels=sorted(set([0] + emergency_channel_ids)),
         )
 
     def to_json(self) -> str:
         return json.dumps({
             "subscribe_root_key": list(self.subscribe_root_key),
             "subscribe_private_key": list(self.subscribe_private_key),
+            "previous_subscribe_private_keys": [
+                list(private_key) for private_key in self.previous_subscribe_private_keys
+            ],
+            "signing_previous_key_index": self.signing_previous_key_index,
             "channels": {
                 channel_id: {
                     "root_key": list(channel.root_key),
@@ -138,6 +201,7 @@ class GlobalSecrets:
                 }
                 for channel_id, channel in self.channels.items()
             },
+            "emergency_channels": self.emergency_channels,
         })
 
     @classmethod
@@ -147,6 +211,12 @@ class GlobalSecrets:
         return cls(
             subscribe_root_key=bytes(data["subscribe_root_key"]),
             subscribe_private_key=bytes(data["subscribe_private_key"]),
+            # older secrets files have never been rotated
+            previous_subscribe_private_keys=[
+                bytes(private_key)
+                for private_key in data.get("previous_subscribe_private_keys", [])
+            ],
+            signing_previous_key_index=data.get("signing_previous_key_index"),
             channels={
                 int(channel_id): ChannelKey(
                     root_key=bytes(channel_json["root_key"]),
@@ -154,6 +224,8 @@ class GlobalSecrets:
                 )
                 for channel_id, channel_json in data["channels"].items()
             },
+            # older secrets files only have channel 0
+            emergency_channels=data.get("emergency_channels", [0]),
         )
 
 
@@ -0,0 +1,50 @@
+import json
+import os
+import unittest
+from unittest import mock
+
+from ectf25_design.gen_secrets import gen_secrets
+from ectf25_design.util import GlobalSecrets
+
+
+class GenSecretsEmergencyChannelsTest(unittest.TestCase):
+    def gen(self, channels: list[int], emergency_channels: str) -> GlobalSecrets:
+        with mock.patch.dict(os.environ, {"EMERGENCY_CHANNELS": emergency_channels}):
+            return GlobalSecrets.from_json(gen_secrets(channels).decode())
+
+    def test_only_channel_0_by_default(self):
+        with mock.patch.dict(os.environ):
+            os.environ.pop("EMERGENCY_CHANNELS", None)
+            secrets = GlobalSecrets.from_json(gen_secrets([1, 2]).decode())
+
+        self.assertEqual(secrets.emergency_channels, [0])
+        self.assertEqual(sorted(secrets.channels), [0, 1, 2])
+
+    def test_several_emergency_channels(self):
+        secrets = self.gen([1, 2], "7,5,9")
+
+        # channel 0 is always an emergency channel, and the list is sorted for the build
+        self.assertEqual(secrets.emergency_channels, [0, 5, 7, 9])
+        self.assertEqual(sorted(secrets.channels), [0, 1, 2, 5, 7, 9])
+
+        # every emergency channel gets its own keys
+        emergency_keys = [secrets.channels[channel] for channel in secrets.emergency_channels]
+        self.assertEqual(len({key.root_key for key in emergency_keys}), 4)
+        self.assertEqual(len({key.private_key for key in emergency_keys}), 4)
+
+    def test_duplicate_emergency_channels(self):
+        secrets = self.gen([3], "0,4,4,")
+
+        self.assertEqual(secrets.emergency_channels, [0, 4])
+        self.assertEqual(sorted(secrets.channels), [0, 3, 4])
+
+    def test_emergency_channels_written_to_secrets_file(self):
+        with mock.patch.dict(os.environ, {"EMERGENCY_CHANNELS": "5,6"}):
+            data = json.loads(gen_secrets([1]))
+
+        self.assertEqual(data["emergency_channels"], [0, 5, 6])
+        self.assertEqual(sorted(data["channels"], key=int), ["0", "1", "5", "6"])
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,156 @@
+import unittest
+from unittest import mock
+
+from ectf25_design import gen_subscription
+from ectf25_design.rotate_subscription_key import (
+    finish_subscription_key_rollout,
+    rotate_subscription_key,
+)
+from ectf25_design.util import GlobalSecrets
+
+
+class RotateSubscriptionKeyTest(unittest.TestCase):
+    def setUp(self):
+        self.secrets = GlobalSecrets.generate([1, 2], [5])
+
+    def test_rotation_keeps_previous_key(self):
+        old_key = self.secrets.subscribe_private_key
+
+        self.secrets.rotate_subscription_signing_key()
+
+        self.assertNotEqual(self.secrets.subscribe_private_key, old_key)
+        self.assertEqual(self.secrets.previous_subscribe_private_keys, [old_key])
+
+    def test_keys_older_than_keep_previous_are_revoked(self):
+        keys = [self.secrets.subscribe_private_key]
+        for _ in range(3):
+            self.secrets.rotate_subscription_signing_key(keep_previous=2)
+            keys.insert(0, self.secrets.subscribe_private_key)
+
+        # most recent first, the oldest 2 keys are revoked
+        self.assertEqual(self.secrets.previous_subscribe_private_keys, keys[1:3])
+
+    def test_keep_no_previous_keys(self):
+        self.secrets.rotate_subscription_signing_key(keep_previous=0)
+
+        self.assertEqual(self.secrets.previous_subscribe_private_keys, [])
+
+    def test_rotation_applies_to_every_channel(self):
+        # the signing key is shared by all channels, so no channel keys change
+        channels = dict(self.secrets.channels)
+        self.secrets.rotate_subscription_signing_key()
+
+        self.assertEqual(self.secrets.channels, channels)
+        self.assertEqual(self.secrets.emergency_channels, [0, 5])
+
+    def test_rotate_secrets_file(self):
+        old_key = self.secrets.subscribe_private_key
+
+        rotated = GlobalSecrets.from_json(
+            rotate_subscription_key(self.secrets.to_json().encode(), keep_previous=1).decode()
+        )
+
+        self.assertNotEqual(rotated.subscribe_private_key, old_key)
+        self.assertEqual(rotated.previous_subscribe_private_keys, [old_key])
+        self.assertEqual(rotated.channels, self.secrets.channels)
+
+
+class StagedRotationTest(unittest.TestCase):
+    def setUp(self):
+        self.secrets = GlobalSecrets.generate([1, 2])
+        self.old_key = self.secrets.subscribe_private_key
+
+    def rotate(self, keep_previous: int = 1, staged: bool = True) -> GlobalSecrets:
+        return GlobalSecrets.from_json(
+            rotate_subscription_key(self.secrets.to_json().encode(), keep_previous, staged).decode()
+        )
+
+    def signing_root(self, secrets: GlobalSecrets, decoder_id: int = 7) -> bytes:
+        """Gets the root key the per decoder signing key is derived from."""
+
+        with (
+            mock.patch("ectf25_design.util.derive_key", side_effect=lambda root, _: root),
+            mock.patch("ectf25_design.util.bytes_to_eddsa_key", side_effect=lambda key: key),
+        ):
+            return secrets.subscription_signing_key_for_decoder(decoder_id)
+
+    def test_staged_rotation_signs_with_old_key(self):
+        rotated = self.rotate()
+
+        # decoders built from the rotated secrets accept both keys
+        self.assertNotEqual(rotated.subscribe_private_key, self.old_key)
+        self.assertEqual(rotated.previous_subscribe_private_keys, [self.old_key])
+
+        self.assertEqual(rotated.signing_previous_key_index, 0)
+        self.assertEqual(self.signing_root(rotated), self.old_key)
+
+    def test_unstaged_rotation_signs_with_new_key(self):
+        rotated = self.rotate(staged=False)
+
+        self.assertIsNone(rotated.signing_previous_key_index)
+        self.assertEqual(self.signing_root(rotated), rotated.subscribe_private_key)
+
+    def test_finish_signs_with_new_key(self):
+        rotated = self.rotate()
+        finished = GlobalSecrets.from_json(
+            finish_subscription_key_rollout(rotated.to_json().encode()).decode()
+        )
+
+        self.assertIsNone(finished.signing_previous_key_index)
+        self.assertEqual(finished.subscribe_private_key, rotated.subscribe_private_key)
+        self.assertEqual(self.signing_root(finished), rotated.subscribe_private_key)
+
+    def test_rotating_again_keeps_signing_key(self):
+        self.secrets = self.rotate(keep_previous=2)
+        rotated = self.rotate(keep_previous=2)
+
+        # the old key moved back in the list, but is still the one used
+        self.assertEqual(rotated.previous_subscribe_private_keys[1], self.old_key)
+        self.assertEqual(rotated.signing_previous_key_index, 1)
+        self.assertEqual(self.signing_root(rotated), self.old_key)
+
+    def test_signing_key_cannot_be_revoked(self):
+        self.secrets = self.rotate(keep_previous=1)
+
+        with self.assertRaises(AssertionError):
+            self.rotate(keep_previous=1)
+
+    def test_staged_rotation_needs_previous_key(self):
+        with self.assertRaises(AssertionError):
+            self.rotate(keep_previous=0)
+
+    def test_gen_subscription_signs_with_chosen_previous_key(self):
+        for _ in range(2):
+            self.secrets.rotate_subscription_signing_key(keep_previous=2)
+        self.secrets.sign_with_previous_subscription_key(1)
+        chosen_key = self.secrets.previous_subscribe_private_keys[1]
+
+        # one node covering the whole subscription
+        node = mock.Mock(lowest_timestamp=0, highest_timestamp=10, key=bytes(32))
+        node.depth.return_value = 60
+
+        with (
+            mock.patch("ectf25_design.util.derive_key", side_effect=lambda root, _: root),
+            mock.patch("ectf25_design.util.bytes_to_eddsa_key", side_effect=lambda key: key),
+            mock.patch("ectf25_design.util.ChannelKey.public_key_bytes", return_value=bytes(32)),
+            mock.patch.object(gen_subscription, "generate_subscription_nodes", return_value=[node]),
+            mock.patch.object(gen_subscription, "logger"),
+            mock.patch.object(gen_subscription, "encrypt_payload") as encrypt_payload,
+        ):
+            gen_subscription.gen_subscription(self.secrets.to_json().encode(), 7, 0, 10, 1)
+
+        signing_key = encrypt_payload.call_args.args[3]
+        self.assertEqual(signing_key, chosen_key)
+        self.assertNotEqual(signing_key, self.secrets.subscribe_private_key)
+
+    def test_old_secrets_files_sign_with_current_key(self):
+        data = self.secrets.to_json().replace(', "signing_previous_key_index": null', "")
+        self.assertNotIn("signing_previous_key_index", data)
+
+        secrets = GlobalSecrets.from_json(data)
+        self.assertIsNone(secrets.signing_previous_key_index)
+        self.assertEqual(self.signing_root(secrets), self.old_key)
+
+
+if __name__ == "__main__":
+    unittest.main()